@@ -33,13 +33,7 @@ fn main() {
         let blob = repo.upload_data(&mut store, "https://example.com".as_bytes()).unwrap();
 
         let data = NodeType::Bookmark(Bookmark::new(blob, Some("Example Site".to_string())));
-        let node = NodeRecord::new(
-            NodeId(0),
-            data,
-            smallvec![],
-            "2025-01-01".to_string(),
-            "2025-01-01".to_string()
-        );
+        let node = NodeRecord::new(NodeId(0), data, smallvec![]);
 
         repo.upsert_node(node).unwrap();
     }
@@ -0,0 +1,79 @@
+//! Measures index rebuild and load times for a synthetic archive.
+//!
+//! Usage: `cargo run --release --example index_bench [nodes] [tags]`
+//! (defaults: 1,000,000 nodes and 100,000 tags).
+//!
+//! Targets on an 8-core machine: `rebuild_all_indexes` under 1 s and
+//! `load_from_json` under 10 s.
+
+use std::time::{ Duration, Instant };
+
+use archivum_core::{
+    blob::{ BlobId, Compression, DataBlob, DataBlobMetadata, HashAlgorithm },
+    node::{ NodeId, NodeRecord },
+    node_type::{ Bookmark, NodeType },
+    state::repository::Repository,
+    tag::{ TagId, TagRecord },
+};
+use smallvec::smallvec;
+
+const REBUILD_TARGET: Duration = Duration::from_secs(1);
+const LOAD_TARGET: Duration = Duration::from_secs(10);
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let node_count: u32 = args.get(1).map_or(1_000_000, |n| n.parse().unwrap());
+    let tag_count: u32 = args.get(2).map_or(100_000, |n| n.parse().unwrap());
+
+    let mut repo = Repository::new();
+
+    // a tree with fan-out 10: tag i is a child of tag i / 10
+    let mut paths: Vec<Vec<String>> = Vec::with_capacity(tag_count as usize);
+    for i in 0..tag_count {
+        let mut path = if i < 10 { Vec::new() } else { paths[(i / 10) as usize].clone() };
+        path.push(format!("t{}", i));
+        paths.push(path.clone());
+        repo.tags.insert(TagId(i), TagRecord::new(TagId(i), path, None));
+    }
+
+    let blob = DataBlob::Single {
+        blob: BlobId::from_data(HashAlgorithm::Blake3, b"bench"),
+        metadata: DataBlobMetadata {
+            original_size: 5,
+            compression: Compression::None,
+            encrypted: false,
+        },
+    };
+    let mut seed: u64 = 0x2545f4914f6cdd1d;
+    let mut random_tag = || {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        TagId((seed % (tag_count as u64)) as u32)
+    };
+    for i in 0..node_count {
+        let data = NodeType::Bookmark(Bookmark::new(blob.clone(), None));
+        let tags = smallvec![random_tag(), random_tag(), random_tag()];
+        let node = NodeRecord::new(NodeId(i), data, tags);
+        repo.nodes.insert(NodeId(i), node);
+    }
+
+    println!("{} nodes, {} tags", node_count, tag_count);
+
+    let start = Instant::now();
+    repo.rebuild_all_indexes();
+    report("rebuild_all_indexes", start.elapsed(), REBUILD_TARGET);
+
+    let json = repo.save_to_json().unwrap();
+
+    let start = Instant::now();
+    let loaded = Repository::load_from_json(&json).unwrap();
+    report("load_from_json", start.elapsed(), LOAD_TARGET);
+
+    assert_eq!(loaded.tag_membership.direct_nodes, repo.tag_membership.direct_nodes);
+}
+
+fn report(name: &str, elapsed: Duration, target: Duration) {
+    let verdict = if elapsed <= target { "ok" } else { "SLOW" };
+    println!("{:<22} {:>10.3?} (target {:?}) {}", name, elapsed, target, verdict);
+}
@@ -0,0 +1,21 @@
+//! Serves a directory-backed blob store over HTTP.
+//!
+//! Usage: `blob-server <listen-address> <directory>`
+
+use std::net::TcpListener;
+
+use archivum_core::blob::{ fs::FsBlobStore, server::BlobServer };
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 3 {
+        eprintln!("usage: {} <listen-address> <directory>", args[0]);
+        std::process::exit(2);
+    }
+
+    let store = FsBlobStore::open(&args[2]).expect("failed to open blob directory");
+    let listener = TcpListener::bind(&args[1]).expect("failed to bind listen address");
+
+    println!("serving {} on {}", args[2], args[1]);
+    BlobServer::new(store).serve(&listener).expect("server failed");
+}
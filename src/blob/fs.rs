@@ -0,0 +1,120 @@
+use std::{ fs, io, path::PathBuf };
+
+use crate::blob::{ BlobId, BlobStore, ManagedBlobStore, RootPointer };
+
+/// Name of the file holding the [`RootPointer`]; never a valid blob id.
+const ROOT_FILE: &str = "ROOT";
+
+/// Held while the root is checked and replaced; never a valid blob id.
+const ROOT_LOCK_FILE: &str = "ROOT.lock";
+
+/// A [`BlobStore`] keeping one file per blob in a directory, named by the
+/// blob id's hex form.
+#[derive(Clone, Debug)]
+pub struct FsBlobStore {
+    root: PathBuf,
+}
+
+impl FsBlobStore {
+    /// Opens the store at `root`, creating the directory if needed.
+    pub fn open(root: impl Into<PathBuf>) -> io::Result<Self> {
+        let root = root.into();
+        fs::create_dir_all(&root)?;
+        Ok(Self { root })
+    }
+
+    fn blob_path(&self, blob_id: &BlobId) -> PathBuf {
+        self.root.join(blob_id.to_hex())
+    }
+}
+
+impl BlobStore for FsBlobStore {
+    type Error = io::Error;
+
+    fn upload(&mut self, blob_id: &BlobId, data: &[u8]) -> Result<(), Self::Error> {
+        let path = self.blob_path(blob_id);
+        if path.exists() {
+            return Ok(());
+        }
+
+        // write to a temporary name first so readers never see partial blobs
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, data)?;
+        fs::rename(tmp_path, path)
+    }
+
+    fn download(&self, blob_id: &BlobId) -> Result<Vec<u8>, Self::Error> {
+        fs::read(self.blob_path(blob_id))
+    }
+
+    fn size(&self, blob_id: &BlobId) -> Result<u64, Self::Error> {
+        Ok(fs::metadata(self.blob_path(blob_id))?.len())
+    }
+}
+
+impl ManagedBlobStore for FsBlobStore {
+    fn delete(&mut self, blob_id: &BlobId) -> Result<(), Self::Error> {
+        match fs::remove_file(self.blob_path(blob_id)) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    fn list(&self) -> Result<Vec<BlobId>, Self::Error> {
+        let mut blobs = Vec::new();
+        for entry in fs::read_dir(&self.root)? {
+            let name = entry?.file_name();
+            if let Some(blob_id) = name.to_str().and_then(|name| name.parse().ok()) {
+                blobs.push(blob_id);
+            }
+        }
+        Ok(blobs)
+    }
+
+    fn contains(&self, blob_id: &BlobId) -> Result<bool, Self::Error> {
+        self.blob_path(blob_id).try_exists()
+    }
+}
+
+impl RootPointer for FsBlobStore {
+    fn read_root(&self) -> Result<Option<BlobId>, Self::Error> {
+        let hex = match fs::read_to_string(self.root.join(ROOT_FILE)) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(None);
+            }
+            result => result?,
+        };
+        hex.trim()
+            .parse()
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Writers take a lock file around the check and the replacement; while
+    /// another writer holds it this fails with
+    /// [`AlreadyExists`](io::ErrorKind::AlreadyExists). A lock left behind by
+    /// a crashed writer has to be removed by hand.
+    fn write_root(
+        &mut self,
+        expected: Option<&BlobId>,
+        root: &BlobId
+    ) -> Result<bool, Self::Error> {
+        let lock_path = self.root.join(ROOT_LOCK_FILE);
+        fs::OpenOptions::new().write(true).create_new(true).open(&lock_path)?;
+
+        let result = self.read_root().and_then(|current| {
+            if current.as_ref() != expected {
+                return Ok(false);
+            }
+
+            // replaced in one rename, like blobs, so readers see the old or new root
+            let path = self.root.join(ROOT_FILE);
+            let tmp_path = path.with_extension("tmp");
+            fs::write(&tmp_path, root.to_hex())?;
+            fs::rename(tmp_path, path)?;
+            Ok(true)
+        });
+        fs::remove_file(lock_path)?;
+        result
+    }
+}
@@ -0,0 +1,174 @@
+//! Minimal HTTP/1.1 framing shared by the blob server and client.
+//!
+//! Only what the blob protocol needs: one request per connection, bodies
+//! delimited by `Content-Length`.
+
+use std::{ io::{ self, BufRead, BufReader, Read, Write }, time::Duration };
+
+use crate::blob::BlobParams;
+
+/// Largest body accepted unless configured otherwise; chunks are 32 MiB by
+/// default.
+pub(crate) const DEFAULT_MAX_BODY: u64 = 64 * 1024 * 1024;
+
+/// Room for what encoding adds to a chunk, e.g. the nonce and tag of
+/// encrypted blobs.
+const BODY_OVERHEAD: u64 = 64 * 1024;
+
+/// How long a connection may stall on reading or writing.
+pub(crate) const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Longest start or header line accepted, line break included.
+const MAX_LINE: u64 = 8 * 1024;
+
+/// Most headers accepted in one message.
+const MAX_HEADERS: usize = 100;
+
+/// A part of a message over its limit, so a server can answer with the
+/// matching status.
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TooLarge {
+    #[error("line too long")]
+    Line,
+    #[error("too many headers")]
+    Headers,
+    #[error("body too large")]
+    Body,
+}
+
+impl TooLarge {
+    /// The limit `error` from [`read_message`] reports exceeding, if any.
+    pub fn of(error: &io::Error) -> Option<Self> {
+        error.get_ref()?.downcast_ref::<Self>().copied()
+    }
+}
+
+/// Largest body needed to transfer blobs written with `params`.
+pub(crate) fn max_body_for(params: &BlobParams) -> u64 {
+    (params.chunk_size as u64).saturating_add(BODY_OVERHEAD).max(DEFAULT_MAX_BODY)
+}
+
+pub(crate) struct Message {
+    /// Request line or status line.
+    pub start_line: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Message {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn content_length(&self) -> Option<u64> {
+        self.header("content-length").and_then(|value| value.parse().ok())
+    }
+}
+
+/// Reads a message; the body is only read when `with_body` is set (responses
+/// to `HEAD` carry a length but no body) and rejected above `max_body` bytes.
+pub(crate) fn read_message<R: Read>(
+    reader: R,
+    with_body: bool,
+    max_body: u64
+) -> io::Result<Message> {
+    let mut reader = BufReader::new(reader);
+    let mut message = read_head(&mut reader)?;
+    if with_body {
+        let length = body_length(&message, max_body)?;
+        message.body = read_body(&mut reader, length)?;
+    }
+    Ok(message)
+}
+
+/// Reads a message with its body, calling `reserve` with the body length
+/// before the body is allocated. What `reserve` returns is handed back with
+/// the message, e.g. a guard holding memory budget until the body is dropped.
+pub(crate) fn read_message_reserving<R: Read, T>(
+    reader: R,
+    max_body: u64,
+    reserve: impl FnOnce(u64) -> io::Result<T>
+) -> io::Result<(Message, T)> {
+    let mut reader = BufReader::new(reader);
+    let mut message = read_head(&mut reader)?;
+    let length = body_length(&message, max_body)?;
+    let reserved = reserve(length)?;
+    message.body = read_body(&mut reader, length)?;
+    Ok((message, reserved))
+}
+
+fn read_head<R: Read>(reader: &mut BufReader<R>) -> io::Result<Message> {
+    let mut start_line = String::new();
+    read_line(reader, &mut start_line)?;
+    let start_line = start_line.trim_end().to_string();
+    if start_line.is_empty() {
+        return Err(invalid("empty message"));
+    }
+
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        if read_line(reader, &mut line)? == 0 {
+            return Err(invalid("unexpected end of headers"));
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(too_large(TooLarge::Headers));
+        }
+        let (key, value) = line.split_once(':').ok_or_else(|| invalid("malformed header"))?;
+        headers.push((key.trim().to_string(), value.trim().to_string()));
+    }
+
+    Ok(Message { start_line, headers, body: Vec::new() })
+}
+
+fn body_length(message: &Message, max_body: u64) -> io::Result<u64> {
+    let length = message.content_length().unwrap_or(0);
+    if length > max_body {
+        return Err(too_large(TooLarge::Body));
+    }
+    Ok(length)
+}
+
+fn read_body<R: Read>(reader: &mut BufReader<R>, length: u64) -> io::Result<Vec<u8>> {
+    let mut body = vec![0; length as usize];
+    reader.read_exact(&mut body)?;
+    Ok(body)
+}
+
+pub(crate) fn write_message<W: Write>(
+    mut writer: W,
+    start_line: &str,
+    content_length: u64,
+    body: &[u8]
+) -> io::Result<()> {
+    write!(
+        writer,
+        "{start_line}\r\nContent-Length: {content_length}\r\nConnection: close\r\n\r\n"
+    )?;
+    writer.write_all(body)?;
+    writer.flush()
+}
+
+/// Reads one line of at most [`MAX_LINE`] bytes.
+fn read_line<R: BufRead>(reader: &mut R, line: &mut String) -> io::Result<usize> {
+    let read = reader.by_ref().take(MAX_LINE).read_line(line)?;
+    if read as u64 == MAX_LINE && !line.ends_with('\n') {
+        return Err(too_large(TooLarge::Line));
+    }
+    Ok(read)
+}
+
+fn too_large(limit: TooLarge) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, limit)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
@@ -0,0 +1,543 @@
+use std::{ borrow::Cow, fmt, str::FromStr };
+
+use chacha20poly1305::{ ChaCha20Poly1305, KeyInit, Nonce, aead::Aead };
+use getset::Getters;
+use sha2::{ Digest, Sha256 };
+
+pub mod fs;
+mod http;
+pub mod remote;
+pub mod server;
+pub mod tiered;
+
+pub trait BlobStore {
+    type Error;
+    fn upload(&mut self, blob_id: &BlobId, data: &[u8]) -> Result<(), Self::Error>;
+    fn download(&self, blob_id: &BlobId) -> Result<Vec<u8>, Self::Error>;
+
+    /// Physical size of a stored blob in bytes.
+    ///
+    /// The default implementation downloads the blob; stores that can answer
+    /// this from metadata should override it.
+    fn size(&self, blob_id: &BlobId) -> Result<u64, Self::Error> {
+        self.download(blob_id).map(|data| data.len() as u64)
+    }
+}
+
+/// A [`BlobStore`] whose blobs can be enumerated and removed, as tiering and
+/// the blob server need.
+pub trait ManagedBlobStore: BlobStore {
+    /// Removes a blob. Deleting a blob that is not stored is not an error.
+    fn delete(&mut self, blob_id: &BlobId) -> Result<(), Self::Error>;
+
+    /// Lists every stored blob.
+    fn list(&self) -> Result<Vec<BlobId>, Self::Error>;
+
+    /// Whether the blob is stored, telling a missing blob apart from a failing
+    /// store.
+    ///
+    /// The default implementation lists every blob; stores that can check a
+    /// single blob should override it.
+    fn contains(&self, blob_id: &BlobId) -> Result<bool, Self::Error> {
+        self.list().map(|blobs| blobs.contains(blob_id))
+    }
+}
+
+/// A [`BlobStore`] that also keeps one small mutable pointer next to its
+/// immutable blobs, e.g. to the latest repository
+/// [`Snapshot`](crate::state::snapshot::Snapshot).
+pub trait RootPointer: BlobStore {
+    /// The blob the pointer refers to, `None` if it was never set.
+    fn read_root(&self) -> Result<Option<BlobId>, Self::Error>;
+
+    /// Points the root at `root` if it still refers to `expected` (`None`:
+    /// never set), as one atomic step. Returns `false` without changing
+    /// anything when another writer moved the root in the meantime.
+    fn write_root(&mut self, expected: Option<&BlobId>, root: &BlobId) -> Result<bool, Self::Error>;
+}
+
+/// Hash function used to address a blob.
+#[derive(Debug, Clone, Copy, Default, Hash, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum HashAlgorithm {
+    #[default]
+    Blake3,
+    Sha256,
+}
+
+impl HashAlgorithm {
+    /// Multihash code of the algorithm.
+    pub fn code(self) -> u8 {
+        match self {
+            HashAlgorithm::Blake3 => 0x1e,
+            HashAlgorithm::Sha256 => 0x12,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0x1e => Some(HashAlgorithm::Blake3),
+            0x12 => Some(HashAlgorithm::Sha256),
+            _ => None,
+        }
+    }
+
+    pub fn digest(self, data: &[u8]) -> [u8; DIGEST_LEN] {
+        match self {
+            HashAlgorithm::Blake3 => *blake3::hash(data).as_bytes(),
+            HashAlgorithm::Sha256 => Sha256::digest(data).into(),
+        }
+    }
+}
+
+const DIGEST_LEN: usize = 32;
+
+/// A hash of a raw, encrypted data blob, tagged with the algorithm that
+/// produced it.
+///
+/// Serialized as a multihash in hex (`<code><length><digest>`). Untagged
+/// BLAKE3 hashes written by older versions are still accepted.
+#[derive(Debug, Clone, Hash, Eq, PartialEq, Getters)]
+#[getset(get = "pub with_prefix")]
+pub struct BlobId {
+    algorithm: HashAlgorithm,
+    digest: [u8; DIGEST_LEN],
+}
+
+impl BlobId {
+    pub fn new(algorithm: HashAlgorithm, digest: [u8; DIGEST_LEN]) -> Self {
+        Self { algorithm, digest }
+    }
+
+    /// Hashes `data` with `algorithm`.
+    pub fn from_data(algorithm: HashAlgorithm, data: &[u8]) -> Self {
+        Self::new(algorithm, algorithm.digest(data))
+    }
+
+    /// Checks that `data` hashes to this id, using the id's own algorithm.
+    pub fn verify(&self, data: &[u8]) -> bool {
+        self.algorithm.digest(data) == self.digest
+    }
+
+    pub fn to_hex(&self) -> String {
+        let mut hex = format!("{:02x}{:02x}", self.algorithm.code(), DIGEST_LEN);
+        for byte in self.digest {
+            hex.push_str(&format!("{:02x}", byte));
+        }
+        hex
+    }
+}
+
+impl From<blake3::Hash> for BlobId {
+    fn from(value: blake3::Hash) -> Self {
+        Self::new(HashAlgorithm::Blake3, *value.as_bytes())
+    }
+}
+
+impl fmt::Display for BlobId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_hex())
+    }
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum BlobIdParseError {
+    #[error("invalid hex")]
+    InvalidHex,
+    #[error("unknown hash algorithm code {0:#x}")] UnknownAlgorithm(u8),
+    #[error("invalid digest length {0}")] InvalidLength(usize),
+}
+
+impl FromStr for BlobId {
+    type Err = BlobIdParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if !s.len().is_multiple_of(2) || !s.is_ascii() {
+            return Err(BlobIdParseError::InvalidHex);
+        }
+
+        let bytes = (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| BlobIdParseError::InvalidHex)?;
+
+        let [code, len, digest @ ..] = bytes.as_slice() else {
+            return Err(BlobIdParseError::InvalidLength(0));
+        };
+        let algorithm = HashAlgorithm::from_code(*code).ok_or(
+            BlobIdParseError::UnknownAlgorithm(*code)
+        )?;
+        if *len as usize != DIGEST_LEN || digest.len() != DIGEST_LEN {
+            return Err(BlobIdParseError::InvalidLength(digest.len()));
+        }
+
+        Ok(Self::new(algorithm, digest.try_into().unwrap()))
+    }
+}
+
+impl serde::Serialize for BlobId {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: serde::Serializer {
+        serializer.serialize_str(&self.to_hex())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for BlobId {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: serde::Deserializer<'de> {
+        #[derive(serde::Deserialize)]
+        #[serde(untagged)]
+        enum BlobIdSerde {
+            Multihash(String),
+            LegacyBlake3(blake3::Hash),
+        }
+
+        match BlobIdSerde::deserialize(deserializer)? {
+            BlobIdSerde::Multihash(hex) => hex.parse().map_err(serde::de::Error::custom),
+            BlobIdSerde::LegacyBlake3(hash) => Ok(hash.into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub struct DataBlobMetadata {
+    pub original_size: u64,
+
+    /// Compression of every part, applied before encryption.
+    #[serde(default)]
+    pub compression: Compression,
+
+    /// Whether the parts are encrypted; reading them needs the key.
+    #[serde(default)]
+    pub encrypted: bool,
+}
+
+/// Compression applied to each physical blob.
+#[derive(Debug, Clone, Copy, Default, Hash, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum Compression {
+    #[default]
+    None,
+    /// DEFLATE at a level from 0 (fastest) to 10 (smallest).
+    Deflate(u8),
+}
+
+impl Compression {
+    fn compress<'a>(self, data: &'a [u8]) -> Cow<'a, [u8]> {
+        match self {
+            Compression::None => Cow::Borrowed(data),
+            Compression::Deflate(level) => {
+                Cow::Owned(miniz_oxide::deflate::compress_to_vec(data, level))
+            }
+        }
+    }
+
+    /// Decompresses data that expands to at most `limit` bytes.
+    fn decompress(self, data: Vec<u8>, limit: usize) -> Result<Vec<u8>, BlobError> {
+        match self {
+            Compression::None => Ok(data),
+            Compression::Deflate(_) => {
+                miniz_oxide::inflate
+                    ::decompress_to_vec_with_limit(&data, limit)
+                    .map_err(|_| BlobError::IntegrityCheckFailed)
+            }
+        }
+    }
+}
+
+/// Key for encrypting blob contents with ChaCha20-Poly1305.
+///
+/// Nonces are derived from the plaintext, so equal data still deduplicates
+/// under the same key.
+#[derive(Clone, PartialEq, Eq)]
+pub struct EncryptionKey([u8; 32]);
+
+const NONCE_LEN: usize = 12;
+
+impl EncryptionKey {
+    pub fn new(key: [u8; 32]) -> Self {
+        Self(key)
+    }
+
+    fn seal(&self, data: &[u8]) -> Vec<u8> {
+        let nonce = blake3::keyed_hash(&self.0, data);
+        let nonce = Nonce::from_slice(&nonce.as_bytes()[..NONCE_LEN]);
+        let cipher = ChaCha20Poly1305::new((&self.0).into());
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend(cipher.encrypt(nonce, data).expect("encrypting in memory cannot fail"));
+        sealed
+    }
+
+    fn open(&self, sealed: &[u8]) -> Result<Vec<u8>, BlobError> {
+        if sealed.len() < NONCE_LEN {
+            return Err(BlobError::DecryptionFailed);
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let cipher = ChaCha20Poly1305::new((&self.0).into());
+        cipher.decrypt(Nonce::from_slice(nonce), ciphertext).map_err(|_| BlobError::DecryptionFailed)
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+/// If data does not fit into a single blob, it is split into multiple blobs
+/// and referenced using a BlobManifest. The manifest itself is stored
+/// uncompressed and unencrypted, so blobs can be listed without the key.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct BlobManifest {
+    pub parts: Vec<BlobId>,
+    pub chunk_size: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub enum DataBlob {
+    Single {
+        blob: BlobId,
+        metadata: DataBlobMetadata,
+    },
+    Chunked {
+        manifest: BlobId,
+        metadata: DataBlobMetadata,
+    },
+}
+
+const CHUNK_SIZE: usize = 32 * 1024 * 1024; // 32 MiB
+// const CHUNK_SIZE: usize = 2; // 2 bytes for testing
+
+/// Parameters controlling how data is laid out into blobs.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct BlobParams {
+    /// Data larger than this is split into chunks of this size.
+    pub chunk_size: usize,
+
+    /// Algorithm used to address newly written blobs.
+    pub hash_algorithm: HashAlgorithm,
+
+    #[serde(default)]
+    pub compression: Compression,
+
+    /// Key to encrypt newly written blobs with. Never serialized.
+    #[serde(skip)]
+    pub encryption: Option<EncryptionKey>,
+}
+
+impl Default for BlobParams {
+    fn default() -> Self {
+        Self {
+            chunk_size: CHUNK_SIZE,
+            hash_algorithm: HashAlgorithm::default(),
+            compression: Compression::default(),
+            encryption: None,
+        }
+    }
+}
+
+impl BlobParams {
+    /// Checks that blobs can be written with these parameters.
+    pub fn validate(&self) -> Result<(), BlobError> {
+        if self.chunk_size == 0 {
+            return Err(BlobError::InvalidParams("chunk size must not be zero".to_string()));
+        }
+        if u32::try_from(self.chunk_size).is_err() {
+            return Err(
+                BlobError::InvalidParams(format!("chunk size {} exceeds u32", self.chunk_size))
+            );
+        }
+        if let Compression::Deflate(level) = self.compression && level > 10 {
+            return Err(BlobError::InvalidParams(format!("deflate level {} above 10", level)));
+        }
+        Ok(())
+    }
+
+    /// The bytes stored for one part of the data.
+    fn encode<'a>(&self, data: &'a [u8]) -> Cow<'a, [u8]> {
+        let compressed = self.compression.compress(data);
+        match &self.encryption {
+            Some(key) => Cow::Owned(key.seal(&compressed)),
+            None => compressed,
+        }
+    }
+}
+
+impl DataBlob {
+    pub fn from_data<S: BlobStore>(store: &mut S, data: &[u8]) -> Result<DataBlob, BlobError>
+        where <S as BlobStore>::Error: std::fmt::Debug
+    {
+        Self::from_data_with_params(store, data, &BlobParams::default())
+    }
+
+    pub fn from_data_with_params<S: BlobStore>(
+        store: &mut S,
+        data: &[u8],
+        params: &BlobParams
+    ) -> Result<DataBlob, BlobError>
+        where <S as BlobStore>::Error: std::fmt::Debug
+    {
+        params.validate()?;
+        let size = data.len();
+        let metadata = DataBlobMetadata {
+            original_size: size as u64,
+            compression: params.compression,
+            encrypted: params.encryption.is_some(),
+        };
+
+        // todo: rolling checksum chunking for large data blobs
+        if size > params.chunk_size {
+            let mut chunk_ids: Vec<BlobId> = Vec::new();
+
+            for chunk in data.chunks(params.chunk_size) {
+                let chunk = params.encode(chunk);
+                let blob_id = BlobId::from_data(params.hash_algorithm, &chunk);
+                store
+                    .upload(&blob_id, &chunk)
+                    .map_err(|e| BlobError::StoreError(format!("{:?}", e)))?;
+                chunk_ids.push(blob_id);
+            }
+
+            let manifest = BlobManifest {
+                parts: chunk_ids,
+                chunk_size: params.chunk_size as u32,
+            };
+
+            let manifest_data = serde_json::to_vec(&manifest).unwrap();
+            let manifest_blob_id = BlobId::from_data(params.hash_algorithm, &manifest_data);
+            store
+                .upload(&manifest_blob_id, &manifest_data)
+                .map_err(|e| BlobError::StoreError(format!("{:?}", e)))?;
+
+            return Ok(DataBlob::Chunked {
+                manifest: manifest_blob_id,
+                metadata,
+            });
+        }
+
+        let data = params.encode(data);
+        let blob_id = BlobId::from_data(params.hash_algorithm, &data);
+
+        store.upload(&blob_id, &data).map_err(|e| BlobError::StoreError(format!("{:?}", e)))?;
+
+        Ok(DataBlob::Single {
+            blob: blob_id,
+            metadata,
+        })
+    }
+
+    pub fn retrieve_data<S: BlobStore>(&self, store: &S) -> Result<Vec<u8>, BlobError>
+        where <S as BlobStore>::Error: std::fmt::Debug
+    {
+        self.retrieve_data_with_key(store, None)
+    }
+
+    /// Reads the data back, decrypting it with `key` if it was encrypted.
+    pub fn retrieve_data_with_key<S: BlobStore>(
+        &self,
+        store: &S,
+        key: Option<&EncryptionKey>
+    ) -> Result<Vec<u8>, BlobError>
+        where <S as BlobStore>::Error: std::fmt::Debug
+    {
+        let metadata = self.get_metadata();
+        let key = match (metadata.encrypted, key) {
+            (true, None) => {
+                return Err(BlobError::KeyRequired);
+            }
+            (true, key) => key,
+            (false, _) => None,
+        };
+        let limit = metadata.original_size as usize;
+        let decode = |data: Vec<u8>| {
+            let data = match key {
+                Some(key) => key.open(&data)?,
+                None => data,
+            };
+            metadata.compression.decompress(data, limit)
+        };
+
+        match self {
+            DataBlob::Single { blob, .. } => {
+                let data = store
+                    .download(blob)
+                    .map_err(|e| BlobError::StoreError(format!("{:?}", e)))?;
+
+                if !blob.verify(&data) {
+                    return Err(BlobError::IntegrityCheckFailed);
+                }
+                decode(data)
+            }
+            DataBlob::Chunked { manifest, .. } => {
+                let manifest = Self::load_manifest(store, manifest)?;
+
+                let mut result: Vec<u8> = Vec::new();
+
+                for part_id in manifest.parts {
+                    let chunk_data = store
+                        .download(&part_id)
+                        .map_err(|e| BlobError::StoreError(format!("{:?}", e)))?;
+
+                    if !part_id.verify(&chunk_data) {
+                        return Err(BlobError::IntegrityCheckFailed);
+                    }
+
+                    result.extend_from_slice(&decode(chunk_data)?);
+                }
+
+                Ok(result)
+            }
+        }
+    }
+
+    pub fn get_metadata(&self) -> &DataBlobMetadata {
+        match self {
+            DataBlob::Single { metadata, .. } => metadata,
+            DataBlob::Chunked { metadata, .. } => metadata,
+        }
+    }
+
+    /// Lists every physical blob backing this data blob, including the
+    /// manifest of chunked data.
+    pub fn physical_blobs<S: BlobStore>(&self, store: &S) -> Result<Vec<BlobId>, BlobError>
+        where <S as BlobStore>::Error: std::fmt::Debug
+    {
+        match self {
+            DataBlob::Single { blob, .. } => Ok(vec![blob.clone()]),
+            DataBlob::Chunked { manifest: manifest_id, .. } => {
+                let manifest = Self::load_manifest(store, manifest_id)?;
+
+                let mut blobs = Vec::with_capacity(manifest.parts.len() + 1);
+                blobs.push(manifest_id.clone());
+                blobs.extend(manifest.parts);
+                Ok(blobs)
+            }
+        }
+    }
+
+    fn load_manifest<S: BlobStore>(store: &S, manifest: &BlobId) -> Result<BlobManifest, BlobError>
+        where <S as BlobStore>::Error: std::fmt::Debug
+    {
+        let manifest_data = store
+            .download(manifest)
+            .map_err(|e| BlobError::StoreError(format!("{:?}", e)))?;
+
+        if !manifest.verify(&manifest_data) {
+            return Err(BlobError::IntegrityCheckFailed);
+        }
+
+        serde_json::from_slice(&manifest_data).map_err(|_| BlobError::IntegrityCheckFailed)
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum BlobError {
+    #[error("blob not found")]
+    NotFound,
+    #[error("blob integrity check failed")]
+    IntegrityCheckFailed,
+    #[error("blob is encrypted and no key was given")]
+    KeyRequired,
+    #[error("blob could not be decrypted")]
+    DecryptionFailed,
+    #[error("invalid blob parameters: {0}")] InvalidParams(String),
+    #[error("blob store error: {0}")] StoreError(String),
+}
@@ -0,0 +1,146 @@
+use std::{ io, net::{ SocketAddr, TcpStream }, time::Duration };
+
+use crate::blob::{ BlobId, BlobParams, BlobStore, ManagedBlobStore, http };
+
+#[derive(thiserror::Error, Debug)]
+pub enum RemoteError {
+    #[error("io error: {0}")] Io(#[from] io::Error),
+    #[error("blob not found")]
+    NotFound,
+    #[error("server error {status}: {message}")] Server {
+        status: u16,
+        message: String,
+    },
+    #[error("protocol error: {0}")] Protocol(String),
+    #[error("invalid server address {0:?}")] InvalidAddress(String),
+}
+
+/// A [`BlobStore`] talking to a [`BlobServer`] over HTTP.
+///
+/// [`BlobServer`]: crate::blob::server::BlobServer
+#[derive(Clone, Debug)]
+pub struct RemoteBlobStore {
+    /// `host:port` of the server.
+    address: String,
+    max_body: u64,
+    timeout: Duration,
+}
+
+impl RemoteBlobStore {
+    /// Connects to `host:port`, where host is a domain name or an IP address
+    /// (IPv6 in brackets).
+    pub fn new(address: impl Into<String>) -> Result<Self, RemoteError> {
+        let address = address.into();
+        if !is_valid_address(&address) {
+            return Err(RemoteError::InvalidAddress(address));
+        }
+
+        Ok(Self { address, max_body: http::DEFAULT_MAX_BODY, timeout: http::DEFAULT_TIMEOUT })
+    }
+
+    /// Accepts blobs as large as `params` writes them.
+    pub fn with_params(mut self, params: &BlobParams) -> Self {
+        self.max_body = http::max_body_for(params);
+        self
+    }
+
+    /// How long reading or writing may stall before a request fails.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    fn request(&self, method: &str, path: &str, body: &[u8]) -> Result<http::Message, RemoteError> {
+        let mut stream = TcpStream::connect(&self.address)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        http::write_message(
+            &mut stream,
+            &format!("{method} {path} HTTP/1.1\r\nHost: {}", self.address),
+            body.len() as u64,
+            body
+        )?;
+
+        let response = http::read_message(&mut stream, method != "HEAD", self.max_body)?;
+
+        let status: u16 = response.start_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|code| code.parse().ok())
+            .ok_or_else(|| RemoteError::Protocol(response.start_line.clone()))?;
+
+        match status {
+            200..=299 => Ok(response),
+            404 => Err(RemoteError::NotFound),
+            _ =>
+                Err(RemoteError::Server {
+                    status,
+                    message: String::from_utf8_lossy(&response.body).into_owned(),
+                }),
+        }
+    }
+}
+
+impl BlobStore for RemoteBlobStore {
+    type Error = RemoteError;
+
+    fn upload(&mut self, blob_id: &BlobId, data: &[u8]) -> Result<(), Self::Error> {
+        self.request("PUT", &format!("/blobs/{}", blob_id), data)?;
+        Ok(())
+    }
+
+    fn download(&self, blob_id: &BlobId) -> Result<Vec<u8>, Self::Error> {
+        Ok(self.request("GET", &format!("/blobs/{}", blob_id), &[])?.body)
+    }
+
+    fn size(&self, blob_id: &BlobId) -> Result<u64, Self::Error> {
+        let response = self.request("HEAD", &format!("/blobs/{}", blob_id), &[])?;
+        response
+            .content_length()
+            .ok_or_else(|| RemoteError::Protocol("missing content length".to_string()))
+    }
+}
+
+impl ManagedBlobStore for RemoteBlobStore {
+    fn delete(&mut self, blob_id: &BlobId) -> Result<(), Self::Error> {
+        self.request("DELETE", &format!("/blobs/{}", blob_id), &[])?;
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<BlobId>, Self::Error> {
+        let response = self.request("GET", "/blobs", &[])?;
+        let body = String::from_utf8(response.body).map_err(|e|
+            RemoteError::Protocol(e.to_string())
+        )?;
+
+        body.lines()
+            .filter(|line| !line.is_empty())
+            .map(|line| line.parse().map_err(|e| RemoteError::Protocol(format!("{e}: {line}"))))
+            .collect()
+    }
+
+    fn contains(&self, blob_id: &BlobId) -> Result<bool, Self::Error> {
+        match self.request("HEAD", &format!("/blobs/{}", blob_id), &[]) {
+            Ok(_) => Ok(true),
+            Err(RemoteError::NotFound) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Whether `address` is a `host:port` safe to put into a request line and
+/// `Host` header.
+fn is_valid_address(address: &str) -> bool {
+    if address.parse::<SocketAddr>().is_ok() {
+        return true;
+    }
+    let Some((host, port)) = address.rsplit_once(':') else {
+        return false;
+    };
+    let is_label = |label: &str| {
+        !label.is_empty() &&
+            !label.starts_with('-') &&
+            label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    };
+    port.parse::<u16>().is_ok() && host.split('.').all(is_label)
+}
@@ -0,0 +1,332 @@
+//! HTTP server exposing a [`BlobStore`].
+//!
+//! | Request               | Response                                        |
+//! |-----------------------|-------------------------------------------------|
+//! | `PUT /blobs/<id>`     | `201`, or `422` if the body does not hash to id |
+//! | `GET /blobs/<id>`     | `200` with the blob, or `404`                   |
+//! | `HEAD /blobs/<id>`    | `200` with the blob size as length, or `404`    |
+//! | `DELETE /blobs/<id>`  | `204`, or `405` unless enabled                  |
+//! | `GET /blobs`          | `200` with one id per line                      |
+//!
+//! Ids are [`BlobId`] multihash hex strings. Requests are `503` while the
+//! server is at its connection limit or holds too many request bodies.
+//! Clients are not authenticated, so
+//! `DELETE` is only served after [`BlobServer::with_deletes`].
+
+use std::{
+    io,
+    net::{ TcpListener, TcpStream },
+    sync::{
+        RwLock,
+        RwLockReadGuard,
+        RwLockWriteGuard,
+        atomic::{ AtomicU64, Ordering },
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::blob::{ BlobId, BlobParams, ManagedBlobStore, http };
+
+/// Pause after a failed accept, so a persistent failure such as running out
+/// of file descriptors does not spin.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Bounds protecting a [`BlobServer`] from slow, large or numerous requests.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ServerLimits {
+    /// Largest request body accepted.
+    pub max_body: u64,
+
+    /// How long reading a request or writing a response may stall.
+    pub timeout: Duration,
+
+    /// Connections handled at the same time.
+    pub max_connections: usize,
+
+    /// Request body bytes held in memory at once, across all connections.
+    pub max_buffered: u64,
+}
+
+impl Default for ServerLimits {
+    fn default() -> Self {
+        Self {
+            max_body: http::DEFAULT_MAX_BODY,
+            timeout: http::DEFAULT_TIMEOUT,
+            max_connections: 64,
+            max_buffered: 4 * http::DEFAULT_MAX_BODY,
+        }
+    }
+}
+
+impl ServerLimits {
+    /// Default limits with a body limit fitting blobs written with `params`.
+    pub fn for_params(params: &BlobParams) -> Self {
+        let max_body = http::max_body_for(params);
+        Self { max_body, max_buffered: 4 * max_body, ..Self::default() }
+    }
+}
+
+pub struct BlobServer<S> {
+    store: RwLock<S>,
+    limits: ServerLimits,
+    connections: AtomicU64,
+    buffered: AtomicU64,
+    deletes: bool,
+}
+
+/// Request bodies beyond [`ServerLimits::max_buffered`].
+#[derive(thiserror::Error, Debug)]
+#[error("too many request bodies in memory")]
+struct Busy;
+
+impl<S: ManagedBlobStore> BlobServer<S> where S::Error: std::fmt::Debug {
+    pub fn new(store: S) -> Self {
+        Self::with_limits(store, ServerLimits::default())
+    }
+
+    pub fn with_limits(store: S, limits: ServerLimits) -> Self {
+        Self {
+            store: RwLock::new(store),
+            limits,
+            connections: AtomicU64::new(0),
+            buffered: AtomicU64::new(0),
+            deletes: false,
+        }
+    }
+
+    /// Lets clients remove blobs with `DELETE`. Off by default: any client
+    /// reaching the server could remove any blob.
+    pub fn with_deletes(mut self) -> Self {
+        self.deletes = true;
+        self
+    }
+
+    pub fn into_inner(self) -> S {
+        self.store.into_inner().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Serves connections, each on its own thread. Reads share the store;
+    /// writes wait for exclusive access. Failing to accept a connection is
+    /// logged and does not stop the server.
+    pub fn serve(&self, listener: &TcpListener) -> io::Result<()> where S: Send + Sync {
+        thread::scope(|scope| {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        eprintln!("blob server: failed to accept a connection: {e}");
+                        thread::sleep(ACCEPT_BACKOFF);
+                        continue;
+                    }
+                };
+
+                // checked before spawning, so excess connections cost no thread
+                match self.connection_slot() {
+                    Some(slot) => {
+                        // a broken connection only affects that client
+                        scope.spawn(move || {
+                            let _slot = slot;
+                            self.serve_connection(stream)
+                        });
+                    }
+                    None => {
+                        let _ = reject(stream);
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+
+    pub fn handle_connection(&self, stream: TcpStream) -> io::Result<()> {
+        match self.connection_slot() {
+            Some(_slot) => self.serve_connection(stream),
+            None => reject(stream),
+        }
+    }
+
+    fn serve_connection(&self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(self.limits.timeout))?;
+        stream.set_write_timeout(Some(self.limits.timeout))?;
+
+        let request = http::read_message_reserving(&mut stream, self.limits.max_body, |bytes| {
+            self.reserve(bytes)
+        });
+        let response = match request {
+            Ok((request, _reserved)) => self.handle(&request),
+            Err(e) if e.get_ref().is_some_and(|e| e.is::<Busy>()) => {
+                Response::text(503, "Service Unavailable", &e.to_string())
+            }
+            Err(e) =>
+                match http::TooLarge::of(&e) {
+                    Some(http::TooLarge::Line | http::TooLarge::Headers) => {
+                        Response::text(431, "Request Header Fields Too Large", &e.to_string())
+                    }
+                    Some(http::TooLarge::Body) => {
+                        Response::text(413, "Content Too Large", &e.to_string())
+                    }
+                    None => Response::text(400, "Bad Request", &e.to_string()),
+                }
+        };
+        response.write(&mut stream)
+    }
+
+    /// Takes one of [`ServerLimits::max_connections`], if any is left.
+    fn connection_slot(&self) -> Option<Reserved<'_>> {
+        let active = self.connections.fetch_add(1, Ordering::SeqCst);
+        let slot = Reserved { counter: &self.connections, amount: 1 };
+        (active < self.limits.max_connections as u64).then_some(slot)
+    }
+
+    /// Takes `bytes` of [`ServerLimits::max_buffered`] for a request body.
+    fn reserve(&self, bytes: u64) -> io::Result<Reserved<'_>> {
+        let buffered = self.buffered.fetch_add(bytes, Ordering::SeqCst);
+        let reserved = Reserved { counter: &self.buffered, amount: bytes };
+        if buffered.saturating_add(bytes) > self.limits.max_buffered {
+            return Err(io::Error::other(Busy));
+        }
+        Ok(reserved)
+    }
+
+    fn handle(&self, request: &http::Message) -> Response {
+        let mut parts = request.start_line.split_whitespace();
+        let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+            return Response::text(400, "Bad Request", "malformed request line");
+        };
+
+        if path == "/blobs" || path == "/blobs/" {
+            return match method {
+                "GET" => self.list(),
+                _ => Response::text(405, "Method Not Allowed", method),
+            };
+        }
+
+        let Some(hex) = path.strip_prefix("/blobs/") else {
+            return Response::text(404, "Not Found", path);
+        };
+        let blob_id: BlobId = match hex.parse() {
+            Ok(id) => id,
+            Err(e) => {
+                return Response::text(400, "Bad Request", &e.to_string());
+            }
+        };
+
+        match method {
+            "PUT" => self.put(&blob_id, &request.body),
+            "GET" => self.get(&blob_id),
+            "HEAD" => self.head(&blob_id),
+            "DELETE" if self.deletes => self.delete(&blob_id),
+            _ => Response::text(405, "Method Not Allowed", method),
+        }
+    }
+
+    fn put(&self, blob_id: &BlobId, data: &[u8]) -> Response {
+        // never let a client store data under an id it does not hash to
+        if !blob_id.verify(data) {
+            return Response::text(422, "Unprocessable Entity", "hash mismatch");
+        }
+
+        match self.store_mut().upload(blob_id, data) {
+            Ok(()) => Response::text(201, "Created", ""),
+            Err(e) => Response::text(500, "Internal Server Error", &format!("{:?}", e)),
+        }
+    }
+
+    // the store error type is opaque, so any failure to read counts as missing
+    fn get(&self, blob_id: &BlobId) -> Response {
+        match self.store().download(blob_id) {
+            Ok(data) => Response::new(200, "OK", data),
+            Err(e) => Response::text(404, "Not Found", &format!("{:?}", e)),
+        }
+    }
+
+    fn head(&self, blob_id: &BlobId) -> Response {
+        match self.store().size(blob_id) {
+            Ok(size) => Response::head(200, "OK", size),
+            Err(_) => Response::head(404, "Not Found", 0),
+        }
+    }
+
+    fn delete(&self, blob_id: &BlobId) -> Response {
+        match self.store_mut().delete(blob_id) {
+            Ok(()) => Response::new(204, "No Content", Vec::new()),
+            Err(e) => Response::text(500, "Internal Server Error", &format!("{:?}", e)),
+        }
+    }
+
+    fn list(&self) -> Response {
+        match self.store().list() {
+            Ok(blobs) => {
+                let body: String = blobs
+                    .iter()
+                    .map(|id| format!("{}\n", id))
+                    .collect();
+                Response::new(200, "OK", body.into_bytes())
+            }
+            Err(e) => Response::text(500, "Internal Server Error", &format!("{:?}", e)),
+        }
+    }
+}
+
+/// Answers a connection over the limit without reading its request.
+fn reject(mut stream: TcpStream) -> io::Result<()> {
+    stream.set_write_timeout(Some(http::DEFAULT_TIMEOUT))?;
+    Response::text(503, "Service Unavailable", "too many connections").write(&mut stream)
+}
+
+/// A share of a server limit, given back when dropped.
+struct Reserved<'a> {
+    counter: &'a AtomicU64,
+    amount: u64,
+}
+
+impl Drop for Reserved<'_> {
+    fn drop(&mut self) {
+        self.counter.fetch_sub(self.amount, Ordering::SeqCst);
+    }
+}
+
+impl<S> BlobServer<S> {
+    // a panicking request leaves the store as consistent as the store itself
+    fn store(&self) -> RwLockReadGuard<'_, S> {
+        self.store.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn store_mut(&self) -> RwLockWriteGuard<'_, S> {
+        self.store.write().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+struct Response {
+    status: String,
+    content_length: u64,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn new(code: u16, reason: &str, body: Vec<u8>) -> Self {
+        Self {
+            status: format!("HTTP/1.1 {code} {reason}"),
+            content_length: body.len() as u64,
+            body,
+        }
+    }
+
+    /// A response announcing `content_length` without sending a body.
+    fn head(code: u16, reason: &str, content_length: u64) -> Self {
+        Self {
+            status: format!("HTTP/1.1 {code} {reason}"),
+            content_length,
+            body: Vec::new(),
+        }
+    }
+
+    fn text(code: u16, reason: &str, message: &str) -> Self {
+        Self::new(code, reason, message.as_bytes().to_vec())
+    }
+
+    fn write(&self, stream: &mut TcpStream) -> io::Result<()> {
+        http::write_message(stream, &self.status, self.content_length, &self.body)
+    }
+}
@@ -0,0 +1,271 @@
+use std::{
+    cell::{ Ref, RefCell },
+    collections::{ HashMap, HashSet },
+    time::{ Duration, SystemTime, UNIX_EPOCH },
+};
+
+use crate::blob::{ BlobId, BlobStore, ManagedBlobStore, RootPointer };
+
+/// Decides which blobs must stay on the hot tier.
+pub trait TieringPolicy {
+    /// Pinned blobs are never demoted to cold storage, and are always
+    /// promoted when read from it.
+    fn is_pinned(&self, blob_id: &BlobId) -> bool;
+
+    /// Whether reading an unpinned cold blob moves it back to the hot tier.
+    /// Reads of blobs left cold are served from the cold store directly.
+    fn promote_on_read(&self, _blob_id: &BlobId) -> bool {
+        true
+    }
+}
+
+/// Policy that pins nothing.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoPinning;
+
+impl TieringPolicy for NoPinning {
+    fn is_pinned(&self, _blob_id: &BlobId) -> bool {
+        false
+    }
+}
+
+/// Pins an explicit set of blobs, e.g. from [`Repository::blobs_for_tags`].
+///
+/// [`Repository::blobs_for_tags`]: crate::state::repository::Repository::blobs_for_tags
+impl TieringPolicy for HashSet<BlobId> {
+    fn is_pinned(&self, blob_id: &BlobId) -> bool {
+        self.contains(blob_id)
+    }
+}
+
+#[derive(Debug)]
+pub enum TieredError<H, C> {
+    Hot(H),
+    Cold(C),
+    IntegrityCheckFailed,
+}
+
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct TieringReport {
+    pub demoted: usize,
+    pub demoted_bytes: u64,
+    pub pinned: usize,
+}
+
+/// A [`BlobStore`] keeping recently read blobs on a hot backend and moving
+/// idle ones to a cold backend.
+///
+/// Reads and writes record an access time per blob. [`demote_idle`] moves
+/// blobs not accessed for a given duration to the cold store; reading a cold
+/// blob promotes it back to the hot store unless the [`TieringPolicy`] says
+/// otherwise.
+///
+/// [`demote_idle`]: TieredBlobStore::demote_idle
+#[derive(Debug)]
+pub struct TieredBlobStore<H, C, P = NoPinning> {
+    hot: RefCell<H>,
+    cold: RefCell<C>,
+    policy: P,
+
+    /// Last access per blob, in seconds since the Unix epoch.
+    access_times: RefCell<HashMap<BlobId, u64>>,
+}
+
+impl<H: ManagedBlobStore, C: ManagedBlobStore> TieredBlobStore<H, C, NoPinning> {
+    pub fn new(hot: H, cold: C) -> Self {
+        Self::with_policy(hot, cold, NoPinning)
+    }
+}
+
+impl<H: ManagedBlobStore, C: ManagedBlobStore, P: TieringPolicy> TieredBlobStore<H, C, P> {
+    pub fn with_policy(hot: H, cold: C, policy: P) -> Self {
+        Self {
+            hot: RefCell::new(hot),
+            cold: RefCell::new(cold),
+            policy,
+            access_times: RefCell::new(HashMap::new()),
+        }
+    }
+
+    pub fn hot(&self) -> Ref<'_, H> {
+        self.hot.borrow()
+    }
+
+    pub fn cold(&self) -> Ref<'_, C> {
+        self.cold.borrow()
+    }
+
+    pub fn policy(&self) -> &P {
+        &self.policy
+    }
+
+    pub fn set_policy(&mut self, policy: P) {
+        self.policy = policy;
+    }
+
+    /// Recorded access times, for persisting between sessions.
+    pub fn access_times(&self) -> HashMap<BlobId, u64> {
+        self.access_times.borrow().clone()
+    }
+
+    pub fn restore_access_times(&mut self, access_times: HashMap<BlobId, u64>) {
+        self.access_times = RefCell::new(access_times);
+    }
+
+    /// Moves hot blobs not accessed for `max_idle` to the cold store.
+    pub fn demote_idle(
+        &mut self,
+        max_idle: Duration
+    ) -> Result<TieringReport, TieredError<H::Error, C::Error>> {
+        self.demote_idle_at(SystemTime::now(), max_idle)
+    }
+
+    /// Like [`demote_idle`](Self::demote_idle) with an explicit current time.
+    ///
+    /// Hot blobs without a recorded access (e.g. written before tiering was
+    /// set up) are considered accessed at `now`.
+    pub fn demote_idle_at(
+        &mut self,
+        now: SystemTime,
+        max_idle: Duration
+    ) -> Result<TieringReport, TieredError<H::Error, C::Error>> {
+        let now = unix_secs(now);
+        let mut report = TieringReport::default();
+
+        let hot_blobs = self.hot.get_mut().list().map_err(TieredError::Hot)?;
+        for blob_id in hot_blobs {
+            let last_access = *self.access_times.get_mut().entry(blob_id.clone()).or_insert(now);
+            if now.saturating_sub(last_access) < max_idle.as_secs() {
+                continue;
+            }
+
+            if self.policy.is_pinned(&blob_id) {
+                report.pinned += 1;
+                continue;
+            }
+
+            let data = self.hot.get_mut().download(&blob_id).map_err(TieredError::Hot)?;
+            if !blob_id.verify(&data) {
+                return Err(TieredError::IntegrityCheckFailed);
+            }
+
+            self.cold.get_mut().upload(&blob_id, &data).map_err(TieredError::Cold)?;
+            self.hot.get_mut().delete(&blob_id).map_err(TieredError::Hot)?;
+
+            report.demoted += 1;
+            report.demoted_bytes += data.len() as u64;
+        }
+
+        Ok(report)
+    }
+
+    /// Runs `read` on the hot tier; `None` if the blob is not there. Other
+    /// hot tier failures are errors rather than a reason to try cold.
+    fn read_hot<T>(
+        &self,
+        blob_id: &BlobId,
+        read: impl FnOnce(&H) -> Result<T, H::Error>
+    ) -> Result<Option<T>, TieredError<H::Error, C::Error>> {
+        let hot = self.hot.borrow();
+        match read(&hot) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) => {
+                if hot.contains(blob_id).map_err(TieredError::Hot)? {
+                    Err(TieredError::Hot(e))
+                } else {
+                    Ok(None)
+                }
+            }
+        }
+    }
+
+    fn touch(&self, blob_id: &BlobId) {
+        self.access_times.borrow_mut().insert(blob_id.clone(), unix_secs(SystemTime::now()));
+    }
+}
+
+impl<H: ManagedBlobStore, C: ManagedBlobStore, P: TieringPolicy> BlobStore
+for TieredBlobStore<H, C, P> {
+    type Error = TieredError<H::Error, C::Error>;
+
+    fn upload(&mut self, blob_id: &BlobId, data: &[u8]) -> Result<(), Self::Error> {
+        self.hot.get_mut().upload(blob_id, data).map_err(TieredError::Hot)?;
+        self.touch(blob_id);
+        Ok(())
+    }
+
+    fn download(&self, blob_id: &BlobId) -> Result<Vec<u8>, Self::Error> {
+        if let Some(data) = self.read_hot(blob_id, |hot| hot.download(blob_id))? {
+            self.touch(blob_id);
+            return Ok(data);
+        }
+
+        let data = self.cold.borrow().download(blob_id).map_err(TieredError::Cold)?;
+        if !blob_id.verify(&data) {
+            return Err(TieredError::IntegrityCheckFailed);
+        }
+
+        if self.policy.is_pinned(blob_id) || self.policy.promote_on_read(blob_id) {
+            self.hot.borrow_mut().upload(blob_id, &data).map_err(TieredError::Hot)?;
+            self.cold.borrow_mut().delete(blob_id).map_err(TieredError::Cold)?;
+            self.touch(blob_id);
+        }
+
+        Ok(data)
+    }
+
+    fn size(&self, blob_id: &BlobId) -> Result<u64, Self::Error> {
+        if let Some(size) = self.read_hot(blob_id, |hot| hot.size(blob_id))? {
+            return Ok(size);
+        }
+        self.cold.borrow().size(blob_id).map_err(TieredError::Cold)
+    }
+}
+
+impl<H: ManagedBlobStore, C: ManagedBlobStore, P: TieringPolicy> ManagedBlobStore
+for TieredBlobStore<H, C, P> {
+    fn delete(&mut self, blob_id: &BlobId) -> Result<(), Self::Error> {
+        self.hot.get_mut().delete(blob_id).map_err(TieredError::Hot)?;
+        self.cold.get_mut().delete(blob_id).map_err(TieredError::Cold)?;
+        self.access_times.get_mut().remove(blob_id);
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<BlobId>, Self::Error> {
+        let mut blobs: HashSet<BlobId> = self.hot
+            .borrow()
+            .list()
+            .map_err(TieredError::Hot)?
+            .into_iter()
+            .collect();
+        blobs.extend(self.cold.borrow().list().map_err(TieredError::Cold)?);
+        Ok(blobs.into_iter().collect())
+    }
+
+    fn contains(&self, blob_id: &BlobId) -> Result<bool, Self::Error> {
+        Ok(
+            self.hot.borrow().contains(blob_id).map_err(TieredError::Hot)? ||
+                self.cold.borrow().contains(blob_id).map_err(TieredError::Cold)?
+        )
+    }
+}
+
+/// The root pointer lives on the hot tier; it is small and read on every open.
+impl<H: RootPointer + ManagedBlobStore, C: ManagedBlobStore, P: TieringPolicy> RootPointer
+for TieredBlobStore<H, C, P> {
+    fn read_root(&self) -> Result<Option<BlobId>, Self::Error> {
+        self.hot.borrow().read_root().map_err(TieredError::Hot)
+    }
+
+    fn write_root(
+        &mut self,
+        expected: Option<&BlobId>,
+        root: &BlobId
+    ) -> Result<bool, Self::Error> {
+        self.hot.get_mut().write_root(expected, root).map_err(TieredError::Hot)
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
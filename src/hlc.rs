@@ -0,0 +1,45 @@
+//! Hybrid logical clock timestamps.
+
+/// A hybrid logical clock timestamp: wall-clock milliseconds, a counter
+/// ordering stamps issued within the same millisecond, and the replica that
+/// issued it.
+///
+/// Stamps order by time first, so the latest write wins; the replica only
+/// breaks ties between stamps issued concurrently on different devices.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    serde::Serialize,
+    serde::Deserialize
+)]
+pub struct Hlc {
+    pub millis: i64,
+    pub counter: u32,
+    pub replica: u64,
+}
+
+impl Hlc {
+    /// The stamp following `last` at wall-clock time `millis`.
+    pub fn next(last: Hlc, millis: i64, replica: u64) -> Self {
+        let (millis, counter) = if millis > last.millis {
+            (millis, 0)
+        } else {
+            (last.millis, last.counter + 1)
+        };
+        Self { millis, counter, replica }
+    }
+
+    /// Replica id of an actor name, see
+    /// [`Repository::set_actor`](crate::state::repository::Repository::set_actor).
+    pub fn replica_of(actor: &str) -> u64 {
+        let hash = blake3::hash(actor.as_bytes());
+        u64::from_le_bytes(hash.as_bytes()[..8].try_into().expect("hash has 32 bytes"))
+    }
+}
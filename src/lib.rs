@@ -1,6 +1,8 @@
 pub mod node;
 pub mod node_type;
 pub mod tag;
+pub mod hlc;
+pub mod uid;
 
 pub mod blob;
 
@@ -1,11 +1,24 @@
-use std::{ fmt::Debug };
+use std::{ collections::BTreeSet, fmt::Debug };
 
+use chrono::{ DateTime, NaiveDate, NaiveDateTime, Utc };
 use getset::Getters;
 use smallvec::SmallVec;
 
-use crate::{ node_type::NodeType, tag::TagId };
+use crate::{ hlc::Hlc, node_type::NodeType, tag::TagId, uid::Uid };
 
-#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    serde::Serialize,
+    serde::Deserialize
+)]
 pub struct NodeId(pub u32);
 
 impl From<u32> for NodeId {
@@ -14,35 +27,175 @@ impl From<u32> for NodeId {
     }
 }
 
-#[derive(Clone, Debug, Getters, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Getters, serde::Serialize, serde::Deserialize)]
 #[getset(get = "pub with_prefix")]
 pub struct NodeRecord {
-    id: NodeId,
+    pub(crate) id: NodeId,
+
+    /// Identity across repositories, see [`Uid`]. Left nil, the repository
+    /// assigns one when the record is first stored.
+    #[serde(default)]
+    pub(crate) uid: Uid,
 
     pub(crate) deleted: bool,
 
+    /// When the record was moved to the trash; `None` for live records and
+    /// for records deleted before timestamps were kept.
+    #[serde(default)]
+    pub(crate) deleted_at: Option<DateTime<Utc>>,
+
     pub data_ref: NodeType,
     pub(crate) tags: SmallVec<[TagId; 4]>,
 
-    date_created: String,
-    date_updated: String,
+    /// Set when the node is first created; kept on later writes.
+    #[serde(deserialize_with = "deserialize_timestamp")]
+    pub(crate) date_created: DateTime<Utc>,
+
+    /// Bumped by the repository on every write.
+    #[serde(deserialize_with = "deserialize_timestamp")]
+    pub(crate) date_updated: DateTime<Utc>,
+
+    /// When each field was last written, for merging.
+    #[serde(default)]
+    pub(crate) clock: NodeClock,
+}
+
+/// Merge metadata of a node: last-writer-wins stamps for `data_ref` and
+/// `deleted`, and an add-wins set for `tags`.
+///
+/// Every tag addition is identified by the tag and the stamp it was added
+/// with. Removing a tag moves the additions seen so far to `tag_removes`, so
+/// an addition made concurrently elsewhere survives a merge.
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct NodeClock {
+    pub data: Hlc,
+
+    /// Covers `deleted_at` as well.
+    pub deleted: Hlc,
+
+    /// Additions still in effect, ordered by stamp. Tags of records written
+    /// before merging was supported have none and count as added at the zero
+    /// stamp.
+    pub tag_adds: Vec<(TagId, Hlc)>,
+
+    /// Removed additions, ordered by stamp.
+    pub tag_removes: Vec<(TagId, Hlc)>,
+}
+
+impl NodeClock {
+    /// Tag additions in effect for a record with `tags`, including the
+    /// implicit ones of older records.
+    pub(crate) fn effective_adds(&self, tags: &[TagId]) -> Vec<(TagId, Hlc)> {
+        let mut adds = self.tag_adds.clone();
+        for tag in tags {
+            if !adds.iter().any(|(t, _)| t == tag) {
+                adds.push((*tag, Hlc::default()));
+            }
+        }
+        adds.sort_by_key(|(tag, stamp)| (*stamp, *tag));
+        adds
+    }
+
+    /// Tags with an addition in effect, in the order they were first added.
+    pub(crate) fn live_tags(&self) -> SmallVec<[TagId; 4]> {
+        let mut tags = SmallVec::new();
+        for (tag, _) in &self.tag_adds {
+            if !tags.contains(tag) {
+                tags.push(*tag);
+            }
+        }
+        tags
+    }
 }
 
 impl NodeRecord {
-    pub fn new(
-        id: NodeId,
-        data: NodeType,
-        tags: SmallVec<[TagId; 4]>,
-        date_created: String,
-        date_updated: String
-    ) -> Self {
+    /// A new record stamped with the current time.
+    ///
+    /// The dates are no longer passed in: the repository keeps
+    /// `date_updated` current on every write, and imports set the creation
+    /// time with [`with_date_created`](Self::with_date_created).
+    pub fn new(id: NodeId, data: NodeType, tags: SmallVec<[TagId; 4]>) -> Self {
+        let now = Utc::now();
         Self {
             id,
+            uid: Uid::NIL,
             deleted: false,
+            deleted_at: None,
             data_ref: data,
             tags,
-            date_created,
-            date_updated,
+            date_created: now,
+            date_updated: now,
+            clock: NodeClock::default(),
+        }
+    }
+
+    /// Sets the creation time of a new record, e.g. when importing. Ignored
+    /// when the record replaces an existing node.
+    pub fn with_date_created(mut self, date_created: DateTime<Utc>) -> Self {
+        self.date_created = date_created;
+        self
+    }
+
+    /// Replaces a date that could not be read with the record's other date,
+    /// or with the unix epoch if neither could. Returns whether either date
+    /// was replaced.
+    pub(crate) fn replace_unrecognized_dates(&mut self) -> bool {
+        let created = Some(self.date_created).filter(|date| *date != UNRECOGNIZED_TIMESTAMP);
+        let updated = Some(self.date_updated).filter(|date| *date != UNRECOGNIZED_TIMESTAMP);
+        if created.is_some() && updated.is_some() {
+            return false;
+        }
+
+        self.date_created = created.or(updated).unwrap_or(DateTime::UNIX_EPOCH);
+        self.date_updated = updated.unwrap_or(self.date_created);
+        true
+    }
+}
+
+/// Stands in for a node date that could not be read, until
+/// [`NodeRecord::replace_unrecognized_dates`] replaces it when loading ends.
+const UNRECOGNIZED_TIMESTAMP: DateTime<Utc> = DateTime::<Utc>::MIN_UTC;
+
+/// Accepts RFC 3339 timestamps, `YYYY-MM-DD[ HH:MM:SS[.fff]]` strings (read
+/// as UTC) and unix seconds. Anything else reads as
+/// [`UNRECOGNIZED_TIMESTAMP`], so one bad date does not fail the whole load;
+/// the repository reports the record instead.
+fn deserialize_timestamp<'de, D>(deserializer: D) -> Result<DateTime<Utc>, D::Error>
+    where D: serde::Deserializer<'de>
+{
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum Raw {
+        Seconds(i64),
+        Text(String),
+    }
+
+    let timestamp = match <Raw as serde::Deserialize>::deserialize(deserializer)? {
+        Raw::Seconds(seconds) => DateTime::from_timestamp(seconds, 0),
+        Raw::Text(text) => parse_timestamp(text.trim()),
+    };
+
+    Ok(timestamp.unwrap_or(UNRECOGNIZED_TIMESTAMP))
+}
+
+fn parse_timestamp(text: &str) -> Option<DateTime<Utc>> {
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(text) {
+        return Some(timestamp.to_utc());
+    }
+    for format in ["%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S%.f"] {
+        if let Ok(timestamp) = NaiveDateTime::parse_from_str(text, format) {
+            return Some(timestamp.and_utc());
         }
     }
+    NaiveDate::parse_from_str(text, "%Y-%m-%d")
+        .ok()
+        .map(|date| date.and_hms_opt(0, 0, 0).expect("midnight is valid").and_utc())
+}
+
+/// Derived time indexes over live nodes (rebuildable from nodes[*] dates).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NodeTimeIndex {
+    pub created: BTreeSet<(DateTime<Utc>, NodeId)>,
+
+    pub updated: BTreeSet<(DateTime<Utc>, NodeId)>,
 }
@@ -2,13 +2,33 @@ use getset::Getters;
 
 use crate::blob::DataBlob;
 
-#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub enum NodeType {
     File(File),
     Bookmark(Bookmark),
 }
 
-#[derive(Clone, Debug, Getters, serde::Serialize, serde::Deserialize)]
+impl NodeType {
+    pub fn get_data_blob(&self) -> &DataBlob {
+        match self {
+            NodeType::File(file) => file.get_data_ref(),
+            NodeType::Bookmark(bookmark) => bookmark.get_data_ref(),
+        }
+    }
+
+    pub(crate) fn set_data_blob(&mut self, blob: DataBlob) {
+        match self {
+            NodeType::File(file) => {
+                file.data_ref = blob;
+            }
+            NodeType::Bookmark(bookmark) => {
+                bookmark.data_ref = blob;
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Getters, serde::Serialize, serde::Deserialize)]
 
 #[getset(get = "pub with_prefix")]
 pub struct File {
@@ -23,7 +43,7 @@ impl File {
     }
 }
 
-#[derive(Clone, Debug, Getters, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Getters, serde::Serialize, serde::Deserialize)]
 #[getset(get = "pub with_prefix")]
 pub struct Bookmark {
     data_ref: DataBlob,
@@ -0,0 +1,160 @@
+use std::collections::{ HashMap, HashSet };
+
+use crate::{
+    blob::{ BlobError, BlobId, BlobStore },
+    node::NodeId,
+    state::repository::Repository,
+    tag::TagId,
+};
+
+/// Space used by a set of nodes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct StorageUsage {
+    /// Sum of the original sizes of the referenced data.
+    pub logical_size: u64,
+
+    /// Size of the distinct physical blobs (chunks and manifests) referenced.
+    pub physical_size: u64,
+
+    /// Part of `physical_size` that is also referenced by nodes outside the set.
+    pub shared_size: u64,
+}
+
+impl StorageUsage {
+    /// Logical bytes per stored physical byte; `1.0` when nothing is stored.
+    pub fn dedup_ratio(&self) -> f64 {
+        if self.physical_size == 0 {
+            return 1.0;
+        }
+        (self.logical_size as f64) / (self.physical_size as f64)
+    }
+
+    /// Physical bytes only referenced from within the set.
+    pub fn exclusive_size(&self) -> u64 {
+        self.physical_size - self.shared_size
+    }
+}
+
+/// Storage accounting for every live node, every live tag subtree and the
+/// whole archive.
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct StorageReport {
+    pub archive: StorageUsage,
+    pub nodes: HashMap<NodeId, StorageUsage>,
+
+    /// Usage of the nodes tagged with the tag or any of its descendants.
+    pub tags: HashMap<TagId, StorageUsage>,
+}
+
+impl StorageReport {
+    pub fn node(&self, node: NodeId) -> Option<&StorageUsage> {
+        self.nodes.get(&node)
+    }
+
+    pub fn tag(&self, tag: TagId) -> Option<&StorageUsage> {
+        self.tags.get(&tag)
+    }
+}
+
+/// Physical blob references of all live nodes.
+struct BlobUsage {
+    sizes: Vec<u64>,
+    /// Number of live nodes referencing each blob.
+    ref_counts: Vec<u32>,
+    node_blobs: HashMap<NodeId, (u64, Vec<usize>)>,
+}
+
+impl BlobUsage {
+    fn collect<S: BlobStore>(repo: &Repository, store: &S) -> Result<Self, BlobError>
+        where <S as BlobStore>::Error: std::fmt::Debug
+    {
+        let mut blob_index: HashMap<BlobId, usize> = HashMap::new();
+        let mut sizes: Vec<u64> = Vec::new();
+        let mut ref_counts: Vec<u32> = Vec::new();
+        let mut node_blobs = HashMap::new();
+
+        for node in repo.iter_nodes() {
+            let data = node.data_ref.get_data_blob();
+
+            let mut blobs: Vec<usize> = Vec::new();
+            for blob_id in data.physical_blobs(store)? {
+                let ix = match blob_index.get(&blob_id) {
+                    Some(ix) => *ix,
+                    None => {
+                        let size = store
+                            .size(&blob_id)
+                            .map_err(|e| BlobError::StoreError(format!("{:?}", e)))?;
+                        sizes.push(size);
+                        ref_counts.push(0);
+                        blob_index.insert(blob_id, sizes.len() - 1);
+                        sizes.len() - 1
+                    }
+                };
+
+                // the same chunk may appear several times within one node
+                if !blobs.contains(&ix) {
+                    blobs.push(ix);
+                    ref_counts[ix] += 1;
+                }
+            }
+
+            node_blobs.insert(*node.get_id(), (data.get_metadata().original_size, blobs));
+        }
+
+        Ok(Self { sizes, ref_counts, node_blobs })
+    }
+
+    fn usage_of<'a>(&self, nodes: impl Iterator<Item = &'a NodeId>) -> StorageUsage {
+        let mut usage = StorageUsage::default();
+        let mut refs_in_set: HashMap<usize, u32> = HashMap::new();
+
+        for node in nodes {
+            let Some((logical_size, blobs)) = self.node_blobs.get(node) else {
+                continue;
+            };
+            usage.logical_size += logical_size;
+            for ix in blobs {
+                *refs_in_set.entry(*ix).or_default() += 1;
+            }
+        }
+
+        for (ix, count) in refs_in_set {
+            usage.physical_size += self.sizes[ix];
+            if self.ref_counts[ix] > count {
+                usage.shared_size += self.sizes[ix];
+            }
+        }
+
+        usage
+    }
+}
+
+impl Repository {
+    /// Computes logical, physical and shared sizes per node, per tag subtree
+    /// and for the whole archive. Only live nodes and tags are accounted.
+    pub fn storage_report<S: BlobStore>(&self, store: &S) -> Result<StorageReport, BlobError>
+        where <S as BlobStore>::Error: std::fmt::Debug
+    {
+        let usage = BlobUsage::collect(self, store)?;
+
+        let archive = usage.usage_of(usage.node_blobs.keys());
+
+        let nodes = usage.node_blobs
+            .keys()
+            .map(|node| (*node, usage.usage_of(std::iter::once(node))))
+            .collect();
+
+        let mut tags = HashMap::new();
+        for tag in self.iter_tags() {
+            let tag_id = *tag.get_id();
+            let members: HashSet<NodeId> = self
+                .tag_subtree_bitmap(tag_id)
+                .iter()
+                .map(NodeId)
+                .collect();
+            tags.insert(tag_id, usage.usage_of(members.iter()));
+        }
+
+        Ok(StorageReport { archive, nodes, tags })
+    }
+}
@@ -0,0 +1,429 @@
+//! Structural differences between repositories.
+//!
+//! [`Repository::diff`] matches records by [`Uid`], so it compares copies of
+//! one repository (a backup, a snapshot, another device) even after merges
+//! gave their records different local ids. Nodes refer to tags by uid as
+//! well, which lets a [`RepoDiff`] be applied as a patch to any repository
+//! holding the records it was computed from.
+
+use std::collections::{ BTreeSet, HashSet };
+
+use chrono::{ DateTime, Utc };
+
+use crate::{
+    node::{ NodeId, NodeRecord },
+    node_type::NodeType,
+    state::{
+        oplog::Operation,
+        repository::{ RepoError, Repository, SetTagPathOptions, is_valid_tag_path },
+    },
+    tag::{ TagColors, TagId, TagRecord },
+    uid::Uid,
+};
+
+/// Changes turning one repository into another, see [`Repository::diff`].
+///
+/// Added and removed records are physically present on one side only; moving
+/// a record to the trash shows up as a change of `deleted`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct RepoDiff {
+    pub nodes_added: Vec<NodeState>,
+    pub nodes_removed: Vec<NodeState>,
+    pub nodes_modified: Vec<NodeDiff>,
+    pub tags_added: Vec<TagState>,
+    pub tags_removed: Vec<TagState>,
+    pub tags_modified: Vec<TagDiff>,
+}
+
+/// A node as a diff refers to it. `id` is the local id in the repository the
+/// record was taken from.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct NodeState {
+    pub uid: Uid,
+    pub id: NodeId,
+    pub data: NodeType,
+    pub deleted: bool,
+    pub tags: Vec<Uid>,
+    pub date_created: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct TagState {
+    pub uid: Uid,
+    pub id: TagId,
+    pub path: Vec<String>,
+    pub color: TagColors,
+    pub deleted: bool,
+}
+
+/// Old and new value of a field.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct FieldChange<T> {
+    pub before: T,
+    pub after: T,
+}
+
+/// Changes of a node present on both sides; `None` for unchanged fields.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct NodeDiff {
+    pub uid: Uid,
+    pub id: NodeId,
+    pub data: Option<FieldChange<NodeType>>,
+    pub deleted: Option<FieldChange<bool>>,
+    pub tags_added: Vec<Uid>,
+    pub tags_removed: Vec<Uid>,
+}
+
+/// Changes of a tag present on both sides; `None` for unchanged fields.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct TagDiff {
+    pub uid: Uid,
+    pub id: TagId,
+    pub path: Option<FieldChange<Vec<String>>>,
+    pub color: Option<FieldChange<TagColors>>,
+    pub deleted: Option<FieldChange<bool>>,
+}
+
+impl RepoDiff {
+    pub fn is_empty(&self) -> bool {
+        self.nodes_added.is_empty() &&
+            self.nodes_removed.is_empty() &&
+            self.nodes_modified.is_empty() &&
+            self.tags_added.is_empty() &&
+            self.tags_removed.is_empty() &&
+            self.tags_modified.is_empty()
+    }
+
+    /// Orders every list by id.
+    pub(crate) fn sort(&mut self) {
+        self.tags_added.sort_by_key(|tag| tag.id);
+        self.tags_removed.sort_by_key(|tag| tag.id);
+        self.tags_modified.sort_by_key(|tag| tag.id);
+        self.nodes_added.sort_by_key(|node| node.id);
+        self.nodes_removed.sort_by_key(|node| node.id);
+        self.nodes_modified.sort_by_key(|node| node.id);
+    }
+}
+
+impl TagDiff {
+    /// The last path segment changed under the same parent.
+    pub fn is_rename(&self) -> bool {
+        self.path.as_ref().is_some_and(|path| parent(&path.before) == parent(&path.after))
+    }
+
+    /// The tag ended up under a different parent.
+    pub fn is_move(&self) -> bool {
+        self.path.as_ref().is_some_and(|path| parent(&path.before) != parent(&path.after))
+    }
+}
+
+impl Repository {
+    /// The changes turning this repository into `other`, listed in id order.
+    pub fn diff(&self, other: &Repository) -> RepoDiff {
+        let mut diff = RepoDiff::default();
+
+        let (ours, theirs) = (&self.uids.tags, &other.uids.tags);
+        for (uid, id) in ours {
+            let tag = &self.tags[id];
+            match theirs.get(uid) {
+                Some(other_id) => {
+                    diff.tags_modified.extend(diff_tags(*uid, tag, &other.tags[other_id]));
+                }
+                None => diff.tags_removed.push(tag_state(*uid, tag)),
+            }
+        }
+        for (uid, id) in theirs {
+            if !ours.contains_key(uid) {
+                diff.tags_added.push(tag_state(*uid, &other.tags[id]));
+            }
+        }
+
+        let (our_nodes, their_nodes) = (&self.uids.nodes, &other.uids.nodes);
+        for (uid, id) in our_nodes {
+            let node = node_state(self, *uid, &self.nodes[id]);
+            match their_nodes.get(uid) {
+                Some(other_id) => {
+                    let theirs = node_state(other, *uid, &other.nodes[other_id]);
+                    diff.nodes_modified.extend(diff_nodes(node, theirs));
+                }
+                None => diff.nodes_removed.push(node),
+            }
+        }
+        for (uid, id) in their_nodes {
+            if !our_nodes.contains_key(uid) {
+                diff.nodes_added.push(node_state(other, *uid, &other.nodes[id]));
+            }
+        }
+
+        diff.sort();
+        diff
+    }
+
+    /// Applies `diff` as one logged, undoable edit.
+    ///
+    /// Every field the diff changes and every record it removes must still be
+    /// as the diff saw it, and added records must not exist yet; otherwise
+    /// nothing is applied. Tags are added to and removed from nodes as sets,
+    /// so those changes apply whatever the node's other tags are. Added
+    /// records keep their id if it is free here.
+    ///
+    /// A tag moved by the patch takes its descendants along like
+    /// [`set_tag_path`](Self::set_tag_path), including ones created here
+    /// since the diff was taken. Fails with [`RepoError::InvalidTagPath`] for
+    /// malformed paths and [`RepoError::PathConflict`] if a live tag would end
+    /// up sharing its path with another.
+    pub fn apply_diff(&mut self, diff: &RepoDiff) -> Result<(), RepoError> {
+        self.logged(Operation::Patch(diff.clone()), |repo| {
+            // ids nodes refer to without a tag record must stay unused, or
+            // the nodes would pick up the added tag
+            let mut taken: HashSet<TagId> = repo.tags.keys().copied().collect();
+            taken.extend(repo.nodes.values().flat_map(|node| node.tags.iter().copied()));
+
+            for added in &diff.tags_added {
+                if repo.uids.tags.contains_key(&added.uid) {
+                    return Err(conflict("tag", added.uid, "already exists"));
+                }
+                if !is_valid_tag_path(&added.path) {
+                    return Err(RepoError::InvalidTagPath);
+                }
+                let mut id = added.id;
+                if taken.contains(&id) {
+                    id = repo.next_tag_id;
+                    while taken.contains(&id) {
+                        id.0 += 1;
+                    }
+                }
+                taken.insert(id);
+                let mut tag = TagRecord::new(id, added.path.clone(), Some(added.color));
+                tag.uid = added.uid;
+                tag.deleted = added.deleted;
+                repo.next_tag_id = repo.next_tag_id.max(TagId(id.0 + 1));
+                repo.write_tag(tag);
+            }
+
+            for removed in &diff.tags_removed {
+                let id = repo.local_tag(removed.uid)?;
+                if tag_state(removed.uid, &repo.tags[&id]) != (TagState { id, ..removed.clone() }) {
+                    return Err(conflict("tag", removed.uid, "changed"));
+                }
+            }
+
+            // every change is checked before any tag moves, as a move takes
+            // the descendants along
+            let mut modified = Vec::new();
+            for change in &diff.tags_modified {
+                let id = repo.local_tag(change.uid)?;
+                let mut tag = repo.tags[&id].clone();
+                apply_field(&mut tag.path, &change.path, "tag", change.uid, "path")?;
+                apply_field(&mut tag.color, &change.color, "tag", change.uid, "color")?;
+                apply_field(&mut tag.deleted, &change.deleted, "tag", change.uid, "deleted")?;
+                if !is_valid_tag_path(&tag.path) {
+                    return Err(RepoError::InvalidTagPath);
+                }
+                modified.push(tag);
+            }
+            let mut moves = Vec::new();
+            for mut tag in modified {
+                let current = &repo.tags[&tag.id];
+                if current.path != tag.path {
+                    moves.push((current.path.len(), tag.id, tag.path.clone()));
+                    tag.path = current.path.clone();
+                }
+                repo.write_tag(tag);
+            }
+
+            // shallowest first; descendants already taken along are skipped
+            moves.sort();
+            for (_, id, path) in moves {
+                if repo.tags[&id].path == path {
+                    continue;
+                }
+                if repo.is_live_tag(id) {
+                    let path = path.iter().map(String::as_str).collect();
+                    repo.set_tag_path_with(id, path, SetTagPathOptions::default())?;
+                } else {
+                    let mut tag = repo.tags[&id].clone();
+                    tag.path = path;
+                    repo.write_tag(tag);
+                }
+            }
+
+            // a move takes along added tags below the old path, which the
+            // patch may have put there for the moved tag to leave
+            for added in &diff.tags_added {
+                let mut tag = repo.tags[&repo.local_tag(added.uid)?].clone();
+                if tag.path != added.path {
+                    tag.path = added.path.clone();
+                    repo.write_tag(tag);
+                }
+            }
+
+            // checked once every tag is in place, so an added tag may take the
+            // path of one the patch moves away
+            let placed = diff.tags_added
+                .iter()
+                .map(|added| added.uid)
+                .chain(diff.tags_modified.iter().map(|change| change.uid));
+            for uid in placed {
+                let tag = &repo.tags[&repo.local_tag(uid)?];
+                let path = tag.path.join("/");
+                if !tag.deleted && repo.tag_paths.shadowed.contains_key(&path) {
+                    return Err(RepoError::PathConflict(path));
+                }
+            }
+
+            for added in &diff.nodes_added {
+                if repo.uids.nodes.contains_key(&added.uid) {
+                    return Err(conflict("node", added.uid, "already exists"));
+                }
+                let id = if repo.nodes.contains_key(&added.id) {
+                    repo.get_next_node_id()
+                } else {
+                    added.id
+                };
+                let tags = added.tags
+                    .iter()
+                    .map(|uid| repo.local_tag(*uid))
+                    .collect::<Result<_, _>>()?;
+                let mut node = NodeRecord::new(id, added.data.clone(), tags);
+                node.uid = added.uid;
+                node.deleted = added.deleted;
+                node.date_created = added.date_created;
+                repo.next_node_id = repo.next_node_id.max(NodeId(id.0 + 1));
+                repo.write_node(node);
+            }
+            for change in &diff.nodes_modified {
+                let mut node = repo.nodes[&repo.local_node(change.uid)?].clone();
+                apply_field(&mut node.data_ref, &change.data, "node", change.uid, "data")?;
+                apply_field(&mut node.deleted, &change.deleted, "node", change.uid, "deleted")?;
+                for uid in &change.tags_removed {
+                    let tag = repo.local_tag(*uid)?;
+                    node.tags.retain(|t| *t != tag);
+                }
+                for uid in &change.tags_added {
+                    let tag = repo.local_tag(*uid)?;
+                    if !node.tags.contains(&tag) {
+                        node.tags.push(tag);
+                    }
+                }
+                repo.write_node(node);
+            }
+            for removed in &diff.nodes_removed {
+                let id = repo.local_node(removed.uid)?;
+                let current = node_state(repo, removed.uid, &repo.nodes[&id]);
+                if current != (NodeState { id, ..removed.clone() }) {
+                    return Err(conflict("node", removed.uid, "changed"));
+                }
+                repo.erase_node(id);
+            }
+
+            // after the nodes, which may drop their references first
+            for removed in &diff.tags_removed {
+                let id = repo.local_tag(removed.uid)?;
+                repo.erase_tag(id);
+            }
+
+            Ok(())
+        })
+    }
+
+    fn local_node(&self, uid: Uid) -> Result<NodeId, RepoError> {
+        self.node_by_uid(uid).ok_or_else(|| conflict("node", uid, "not found"))
+    }
+
+    fn local_tag(&self, uid: Uid) -> Result<TagId, RepoError> {
+        self.tag_by_uid(uid).ok_or_else(|| conflict("tag", uid, "not found"))
+    }
+}
+
+fn parent(path: &[String]) -> &[String] {
+    path.split_last().map_or(&[], |(_, parent)| parent)
+}
+
+fn conflict(kind: &str, uid: Uid, reason: &str) -> RepoError {
+    RepoError::PatchConflict(format!("{kind} {uid} {reason}"))
+}
+
+/// Sets a field to its new value if it still has the old one.
+fn apply_field<T: Clone + PartialEq>(
+    field: &mut T,
+    change: &Option<FieldChange<T>>,
+    kind: &str,
+    uid: Uid,
+    name: &str
+) -> Result<(), RepoError> {
+    let Some(change) = change else {
+        return Ok(());
+    };
+    if *field != change.before {
+        return Err(conflict(kind, uid, &format!("{name} changed")));
+    }
+    *field = change.after.clone();
+    Ok(())
+}
+
+pub(crate) fn tag_state(uid: Uid, tag: &TagRecord) -> TagState {
+    TagState {
+        uid,
+        id: tag.id,
+        path: tag.path.clone(),
+        color: tag.color,
+        deleted: tag.deleted,
+    }
+}
+
+/// References to tags the repository does not have are left out.
+pub(crate) fn node_state(repo: &Repository, uid: Uid, node: &NodeRecord) -> NodeState {
+    let tags: BTreeSet<Uid> = node.tags
+        .iter()
+        .filter_map(|id| Some(Uid::of_tag(*id, repo.tags.get(id)?)))
+        .collect();
+    NodeState {
+        uid,
+        id: node.id,
+        data: node.data_ref.clone(),
+        deleted: node.deleted,
+        tags: tags.into_iter().collect(),
+        date_created: node.date_created,
+    }
+}
+
+fn diff_tags(uid: Uid, ours: &TagRecord, theirs: &TagRecord) -> Option<TagDiff> {
+    let diff = TagDiff {
+        uid,
+        id: ours.id,
+        path: field_change(&ours.path, &theirs.path),
+        color: field_change(&ours.color, &theirs.color),
+        deleted: field_change(&ours.deleted, &theirs.deleted),
+    };
+    (diff.path.is_some() || diff.color.is_some() || diff.deleted.is_some()).then_some(diff)
+}
+
+fn diff_nodes(ours: NodeState, theirs: NodeState) -> Option<NodeDiff> {
+    let diff = NodeDiff {
+        uid: ours.uid,
+        id: ours.id,
+        data: field_change(&ours.data, &theirs.data),
+        deleted: field_change(&ours.deleted, &theirs.deleted),
+        tags_added: theirs.tags
+            .iter()
+            .filter(|tag| !ours.tags.contains(tag))
+            .copied()
+            .collect(),
+        tags_removed: ours.tags
+            .iter()
+            .filter(|tag| !theirs.tags.contains(tag))
+            .copied()
+            .collect(),
+    };
+    let changed =
+        diff.data.is_some() ||
+        diff.deleted.is_some() ||
+        !diff.tags_added.is_empty() ||
+        !diff.tags_removed.is_empty();
+    changed.then_some(diff)
+}
+
+pub(crate) fn field_change<T: Clone + PartialEq>(before: &T, after: &T) -> Option<FieldChange<T>> {
+    (before != after).then(|| FieldChange { before: before.clone(), after: after.clone() })
+}
@@ -0,0 +1,257 @@
+//! Change notifications.
+//!
+//! Events are derived once per outermost mutation by comparing each touched
+//! record before and after, and sent to every subscriber over a channel.
+
+use std::{ collections::{ HashMap, HashSet }, sync::mpsc::{ self, Receiver, Sender } };
+
+use crate::{
+    node::{ NodeId, NodeRecord },
+    state::{ history::Change, repository::Repository },
+    tag::{ TagId, TagRecord },
+};
+
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum RepoEvent {
+    NodeAdded(NodeRecord),
+    /// The node's data changed; tag changes are reported as
+    /// [`NodeTagged`](Self::NodeTagged) and [`NodeUntagged`](Self::NodeUntagged).
+    NodeUpdated {
+        before: Box<NodeRecord>,
+        after: Box<NodeRecord>,
+    },
+    /// Moved to the trash.
+    NodeDeleted(NodeId),
+    NodeRestored(NodeId),
+    /// Physically removed.
+    NodeRemoved(NodeId),
+    NodeTagged {
+        node: NodeId,
+        tag: TagId,
+    },
+    NodeUntagged {
+        node: NodeId,
+        tag: TagId,
+    },
+    TagAdded(TagRecord),
+    /// The tag's color changed.
+    TagUpdated {
+        before: TagRecord,
+        after: TagRecord,
+    },
+    TagMoved {
+        tag: TagId,
+        from: Vec<String>,
+        to: Vec<String>,
+    },
+    /// Moved to the trash.
+    TagDeleted(TagId),
+    TagRestored(TagId),
+    /// Physically removed.
+    TagRemoved(TagId),
+}
+
+struct Subscriber {
+    sender: Sender<RepoEvent>,
+
+    /// Only events touching this tag or its descendants are sent.
+    subtree: Option<TagId>,
+}
+
+/// Subscribers of a repository. A cloned repository starts without any.
+#[derive(Default)]
+pub(crate) struct Subscribers(Vec<Subscriber>);
+
+impl Clone for Subscribers {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl std::fmt::Debug for Subscribers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Subscribers({})", self.0.len())
+    }
+}
+
+impl Repository {
+    /// Receives an event for every change made from now on. Dropping the
+    /// receiver unsubscribes.
+    pub fn subscribe(&mut self) -> Receiver<RepoEvent> {
+        self.add_subscriber(None)
+    }
+
+    /// Like [`subscribe`](Self::subscribe), limited to events about `tag`,
+    /// its descendants and nodes tagged with any of them (before or after the
+    /// change).
+    pub fn subscribe_to_subtree(&mut self, tag: TagId) -> Receiver<RepoEvent> {
+        self.add_subscriber(Some(tag))
+    }
+
+    fn add_subscriber(&mut self, subtree: Option<TagId>) -> Receiver<RepoEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.0.push(Subscriber { sender, subtree });
+        receiver
+    }
+}
+
+impl Subscribers {
+    /// Sends the events for `changes`, the earlier versions of the records
+    /// touched by one mutation, given the records after it.
+    pub(crate) fn notify(
+        &mut self,
+        changes: &[Change],
+        nodes: &HashMap<NodeId, NodeRecord>,
+        tags: &HashMap<TagId, TagRecord>
+    ) {
+        if self.0.is_empty() || changes.is_empty() {
+            return;
+        }
+
+        // the earliest version of a record is its state before the mutation
+        let mut seen_nodes = HashSet::new();
+        let mut seen_tags = HashSet::new();
+        let mut node_changes: Vec<(NodeId, Option<&NodeRecord>)> = Vec::new();
+        let mut tag_changes: Vec<(TagId, Option<&TagRecord>)> = Vec::new();
+        for change in changes {
+            match change {
+                Change::Node(id, old) if seen_nodes.insert(*id) => {
+                    node_changes.push((*id, old.as_ref()));
+                }
+                Change::Tag(id, old) if seen_tags.insert(*id) => {
+                    tag_changes.push((*id, old.as_ref()));
+                }
+                _ => {}
+            }
+        }
+
+        let tags_before: HashMap<TagId, Option<&TagRecord>> = tag_changes.iter().copied().collect();
+
+        // each event with the tags it concerns, for subtree filtering
+        let mut events: Vec<(RepoEvent, Vec<TagId>)> = Vec::new();
+        for (id, before) in tag_changes {
+            for event in tag_events(id, before, tags.get(&id)) {
+                events.push((event, vec![id]));
+            }
+        }
+        for (id, before) in node_changes {
+            let after = nodes.get(&id);
+            let mut related: Vec<TagId> = before
+                .into_iter()
+                .chain(after)
+                .flat_map(|node| node.get_tags().iter().copied())
+                .collect();
+            related.sort();
+            related.dedup();
+            for event in node_events(id, before, after) {
+                events.push((event, related.clone()));
+            }
+        }
+
+        // a tag moved into or out of the subtree, or deleted from it, concerns
+        // the subtree on either side of the change
+        let below = |root: Option<&TagRecord>, tag: Option<&TagRecord>| {
+            match (root, tag) {
+                (Some(root), Some(tag)) => tag.get_path().starts_with(root.get_path()),
+                _ => false,
+            }
+        };
+        let before = |id: TagId| tags_before.get(&id).copied().unwrap_or_else(|| tags.get(&id));
+        let in_subtree = |root: TagId, tag: TagId| {
+            root == tag ||
+                below(tags.get(&root), tags.get(&tag)) ||
+                below(before(root), before(tag))
+        };
+
+        // a failed send means the receiver was dropped
+        self.0.retain(|subscriber| {
+            let wanted = |related: &[TagId]| {
+                subscriber.subtree.is_none_or(|root| {
+                    related.iter().any(|tag| in_subtree(root, *tag))
+                })
+            };
+            events
+                .iter()
+                .filter(|(_, related)| wanted(related))
+                .all(|(event, _)| subscriber.sender.send(event.clone()).is_ok())
+        });
+    }
+}
+
+fn node_events(
+    id: NodeId,
+    before: Option<&NodeRecord>,
+    after: Option<&NodeRecord>
+) -> Vec<RepoEvent> {
+    let (before, after) = match (before, after) {
+        (None, None) => {
+            return Vec::new();
+        }
+        (None, Some(after)) => {
+            return vec![RepoEvent::NodeAdded(after.clone())];
+        }
+        (Some(_), None) => {
+            return vec![RepoEvent::NodeRemoved(id)];
+        }
+        (Some(before), Some(after)) => (before, after),
+    };
+
+    let mut events = Vec::new();
+    if before.data_ref != after.data_ref {
+        events.push(RepoEvent::NodeUpdated {
+            before: Box::new(before.clone()),
+            after: Box::new(after.clone()),
+        });
+    }
+    for tag in after.get_tags().iter().filter(|t| !before.get_tags().contains(t)) {
+        events.push(RepoEvent::NodeTagged { node: id, tag: *tag });
+    }
+    for tag in before.get_tags().iter().filter(|t| !after.get_tags().contains(t)) {
+        events.push(RepoEvent::NodeUntagged { node: id, tag: *tag });
+    }
+    match (before.deleted, after.deleted) {
+        (false, true) => events.push(RepoEvent::NodeDeleted(id)),
+        (true, false) => events.push(RepoEvent::NodeRestored(id)),
+        _ => {}
+    }
+
+    events
+}
+
+fn tag_events(
+    id: TagId,
+    before: Option<&TagRecord>,
+    after: Option<&TagRecord>
+) -> Vec<RepoEvent> {
+    let (before, after) = match (before, after) {
+        (None, None) => {
+            return Vec::new();
+        }
+        (None, Some(after)) => {
+            return vec![RepoEvent::TagAdded(after.clone())];
+        }
+        (Some(_), None) => {
+            return vec![RepoEvent::TagRemoved(id)];
+        }
+        (Some(before), Some(after)) => (before, after),
+    };
+
+    let mut events = Vec::new();
+    if before.get_path() != after.get_path() {
+        events.push(RepoEvent::TagMoved {
+            tag: id,
+            from: before.get_path().clone(),
+            to: after.get_path().clone(),
+        });
+    }
+    if before.get_color() != after.get_color() {
+        events.push(RepoEvent::TagUpdated { before: before.clone(), after: after.clone() });
+    }
+    match (before.deleted, after.deleted) {
+        (false, true) => events.push(RepoEvent::TagDeleted(id)),
+        (true, false) => events.push(RepoEvent::TagRestored(id)),
+        _ => {}
+    }
+
+    events
+}
@@ -0,0 +1,269 @@
+//! Undo and redo.
+//!
+//! Every record written through the repository leaves its previous version
+//! behind. The versions left by one outermost mutation (or by everything
+//! inside [`Repository::undo_group`]) form one undo step; undoing puts them
+//! back and turns the overwritten records into a redo step.
+
+use std::{ collections::{ HashMap, VecDeque }, convert::Infallible };
+
+use crate::{
+    node::{ NodeId, NodeRecord },
+    state::{ oplog::Operation, repository::Repository },
+    tag::{ TagId, TagRecord },
+};
+
+const DEFAULT_UNDO_LIMIT: usize = 100;
+
+/// Records to put back, `None` for records to remove.
+pub(crate) type NodeStates = Vec<(NodeId, Option<NodeRecord>)>;
+pub(crate) type TagStates = Vec<(TagId, Option<TagRecord>)>;
+
+/// A record's previous version, `None` if it did not exist.
+#[derive(Clone, Debug)]
+pub(crate) enum Change {
+    Node(NodeId, Option<NodeRecord>),
+    Tag(TagId, Option<TagRecord>),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum Mode {
+    #[default]
+    Edit,
+    Undo,
+    Redo,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct History {
+    undo: VecDeque<Vec<Change>>,
+    redo: Vec<Vec<Change>>,
+
+    /// Changes of the step being built.
+    pending: Vec<Change>,
+    group_depth: usize,
+    limit: usize,
+    mode: Mode,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self {
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+            pending: Vec::new(),
+            group_depth: 0,
+            limit: DEFAULT_UNDO_LIMIT,
+            mode: Mode::Edit,
+        }
+    }
+}
+
+impl History {
+    pub(crate) fn record(&mut self, change: Change) {
+        self.pending.push(change);
+    }
+
+    /// Records the previous version of every record that differs between
+    /// `before` and the current maps.
+    pub(crate) fn record_diff(
+        &mut self,
+        nodes_before: &HashMap<NodeId, NodeRecord>,
+        tags_before: &HashMap<TagId, TagRecord>,
+        nodes: &HashMap<NodeId, NodeRecord>,
+        tags: &HashMap<TagId, TagRecord>
+    ) {
+        let added_nodes = nodes.keys().filter(|id| !nodes_before.contains_key(id));
+        for id in nodes_before.keys().chain(added_nodes) {
+            let old = nodes_before.get(id);
+            if old != nodes.get(id) {
+                self.pending.push(Change::Node(*id, old.cloned()));
+            }
+        }
+
+        let added_tags = tags.keys().filter(|id| !tags_before.contains_key(id));
+        for id in tags_before.keys().chain(added_tags) {
+            let old = tags_before.get(id);
+            if old != tags.get(id) {
+                self.pending.push(Change::Tag(*id, old.cloned()));
+            }
+        }
+    }
+
+    pub(crate) fn mark(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub(crate) fn changes_since(&self, mark: usize) -> &[Change] {
+        &self.pending[mark..]
+    }
+
+    /// Closes the pending step, unless a group is still open.
+    pub(crate) fn commit(&mut self) {
+        if self.group_depth > 0 || self.pending.is_empty() {
+            return;
+        }
+
+        let step = std::mem::take(&mut self.pending);
+        match self.mode {
+            Mode::Edit => {
+                self.redo.clear();
+                self.undo.push_back(step);
+            }
+            Mode::Undo => self.redo.push(step),
+            Mode::Redo => self.undo.push_back(step),
+        }
+        while self.undo.len() > self.limit {
+            self.undo.pop_front();
+        }
+    }
+}
+
+/// The earliest previous version of each record in `changes`, i.e. the state
+/// before all of them.
+fn inverse(changes: Vec<Change>) -> (NodeStates, TagStates) {
+    let mut nodes = HashMap::new();
+    let mut tags = HashMap::new();
+    for change in changes {
+        match change {
+            Change::Node(id, old) => {
+                nodes.entry(id).or_insert(old);
+            }
+            Change::Tag(id, old) => {
+                tags.entry(id).or_insert(old);
+            }
+        }
+    }
+
+    let mut nodes: Vec<_> = nodes.into_iter().collect();
+    let mut tags: Vec<_> = tags.into_iter().collect();
+    nodes.sort_by_key(|(id, _)| *id);
+    tags.sort_by_key(|(id, _)| *id);
+    (nodes, tags)
+}
+
+impl Repository {
+    /// Reverts the last undo step. Returns `false` if there is nothing to
+    /// undo or an [`undo_group`](Self::undo_group) is still open.
+    pub fn undo(&mut self) -> bool {
+        if self.history.group_depth > 0 {
+            return false;
+        }
+        let Some(step) = self.history.undo.pop_back() else {
+            return false;
+        };
+
+        self.replay_step(step, Mode::Undo);
+        true
+    }
+
+    /// Re-applies the last undone step. Any other edit clears the redo stack.
+    pub fn redo(&mut self) -> bool {
+        if self.history.group_depth > 0 {
+            return false;
+        }
+        let Some(step) = self.history.redo.pop() else {
+            return false;
+        };
+
+        self.replay_step(step, Mode::Redo);
+        true
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.history.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.history.redo.is_empty()
+    }
+
+    /// Runs `f` so that all of its edits undo as a single step. If `f` fails,
+    /// the edits it made are reverted and leave no undo step.
+    pub fn undo_group<T, E>(&mut self, f: impl FnOnce(&mut Self) -> Result<T, E>) -> Result<T, E> {
+        let mark = self.history.mark();
+        self.history.group_depth += 1;
+        let result = f(self);
+        if result.is_err() {
+            self.revert_group(mark);
+        }
+        self.history.group_depth -= 1;
+
+        self.history.commit();
+        result
+    }
+
+    /// Keeps at most `limit` undo steps, dropping the oldest ones.
+    pub fn set_undo_limit(&mut self, limit: usize) {
+        self.history.limit = limit;
+        while self.history.undo.len() > limit {
+            self.history.undo.pop_front();
+        }
+    }
+
+    pub fn clear_history(&mut self) {
+        self.history.undo.clear();
+        self.history.redo.clear();
+    }
+
+    /// Reverts the edits of a failed group back to `mark`. They were logged
+    /// and announced one by one, so the reversal is too.
+    fn revert_group(&mut self, mark: usize) {
+        let (nodes, tags) = inverse(self.history.changes_since(mark).to_vec());
+        if !nodes.is_empty() || !tags.is_empty() {
+            let op = Operation::Revert { nodes: nodes.clone(), tags: tags.clone() };
+            let Ok(()) = self.logged(op, |repo| {
+                repo.revert(nodes, tags);
+                Ok::<_, Infallible>(())
+            });
+        }
+        self.history.pending.truncate(mark);
+    }
+
+    fn replay_step(&mut self, step: Vec<Change>, mode: Mode) {
+        let (nodes, tags) = inverse(step);
+        let op = Operation::Revert { nodes: nodes.clone(), tags: tags.clone() };
+
+        self.history.mode = mode;
+        let Ok(()) = self.logged(op, |repo| {
+            repo.revert(nodes, tags);
+            Ok::<_, Infallible>(())
+        });
+        self.history.mode = Mode::Edit;
+    }
+
+    /// Puts records back as given; `None` removes the record. Fields that
+    /// change get fresh merge stamps, so the revert wins over the reverted
+    /// edit when merging.
+    pub(crate) fn revert(&mut self, nodes: NodeStates, tags: TagStates) {
+        for (id, mut tag) in tags {
+            if let Some(tag) = &mut tag {
+                self.stamp_tag(tag);
+                self.next_tag_id = self.next_tag_id.max(TagId(id.0 + 1));
+            }
+            self.put_tag(id, tag);
+        }
+        for (id, mut node) in nodes {
+            if let Some(node) = &mut node {
+                self.stamp_node(node);
+                self.next_node_id = self.next_node_id.max(NodeId(id.0 + 1));
+            }
+            self.put_node(id, node);
+        }
+    }
+
+    /// Undoes the pending changes made after `mark`, e.g. by a failed
+    /// mutation, restoring the records exactly.
+    pub(crate) fn roll_back_to(&mut self, mark: usize) {
+        let changes = self.history.pending.split_off(mark);
+        let (nodes, tags) = inverse(changes);
+
+        for (id, tag) in tags {
+            self.put_tag(id, tag);
+        }
+        for (id, node) in nodes {
+            self.put_node(id, node);
+        }
+        self.history.pending.truncate(mark);
+    }
+}
@@ -0,0 +1,74 @@
+use std::io::Read;
+
+use smallvec::SmallVec;
+
+use crate::{
+    blob::{ BlobStore, DataBlob },
+    node::{ NodeId, NodeRecord },
+    node_type::{ Bookmark, File, NodeType },
+    state::{ repository::{ RepoError, Repository }, transaction::Transaction },
+    tag::TagId,
+};
+
+impl Repository {
+    /// Stores a file and creates a node for it in one step.
+    ///
+    /// `tags` are slash-separated paths like `"media/photos/2024"`; missing
+    /// tags are created. The node and any new tags are added atomically, so
+    /// on error only the uploaded (content-addressed) blobs remain.
+    pub fn add_file<S: BlobStore>(
+        &mut self,
+        store: &mut S,
+        name: &str,
+        mut reader: impl Read,
+        mime_type: Option<&str>,
+        tags: &[&str]
+    ) -> Result<NodeId, RepoError>
+        where <S as BlobStore>::Error: std::fmt::Debug
+    {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        let blob = DataBlob::from_data(store, &data)?;
+
+        let file = File::new(name.to_string(), mime_type.map(|m| m.to_string()), blob);
+        self.add_node(NodeType::File(file), tags)
+    }
+
+    /// Stores a bookmark's URL and creates a node for it in one step. See
+    /// [`add_file`](Self::add_file) for how `tags` are resolved.
+    pub fn add_bookmark<S: BlobStore>(
+        &mut self,
+        store: &mut S,
+        url: &str,
+        title: Option<&str>,
+        tags: &[&str]
+    ) -> Result<NodeId, RepoError>
+        where <S as BlobStore>::Error: std::fmt::Debug
+    {
+        let blob = DataBlob::from_data(store, url.as_bytes())?;
+
+        let bookmark = Bookmark::new(blob, title.map(|t| t.to_string()));
+        self.add_node(NodeType::Bookmark(bookmark), tags)
+    }
+
+    fn add_node(&mut self, data: NodeType, tags: &[&str]) -> Result<NodeId, RepoError> {
+        self.transaction(|tx| {
+            let tags = resolve_tags(tx, tags)?;
+            let node = tx.get_next_node_id();
+            tx.upsert_node(NodeRecord::new(node, data, tags))
+        })
+    }
+}
+
+fn resolve_tags(tx: &mut Transaction<'_>, paths: &[&str]) -> Result<SmallVec<[TagId; 4]>, RepoError> {
+    let mut tags = SmallVec::new();
+    for path in paths {
+        let segments: Vec<&str> = path.split('/').collect();
+        let tag = tx.ensure_tag_path(&segments, None)?;
+        if !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+
+    Ok(tags)
+}
@@ -0,0 +1,289 @@
+use std::{ collections::{ BTreeMap, BTreeSet, HashSet }, convert::Infallible };
+
+use chrono::{ DateTime, Utc };
+
+use crate::{
+    node::NodeId,
+    state::{
+        merge::{ stamp_node_against, stamp_tag_against },
+        oplog::Operation,
+        repository::{ Repository, is_valid_tag_path },
+    },
+    tag::{ TagId, TagRecord },
+    uid::{ Uid, UidKind },
+};
+
+/// A referential integrity problem found by [`Repository::validate`].
+#[derive(
+    Clone,
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    serde::Serialize,
+    serde::Deserialize
+)]
+pub enum IntegrityIssue {
+    /// A node is stored under a different id than its record carries.
+    NodeIdMismatch {
+        key: NodeId,
+        record: NodeId,
+    },
+    /// A tag is stored under a different id than its record carries.
+    TagIdMismatch {
+        key: TagId,
+        record: TagId,
+    },
+    /// A live tag has no segments, an empty segment or a segment containing `/`.
+    InvalidTagPath {
+        tag: TagId,
+    },
+    /// Several live tags share a path; lookups only see the lowest id.
+    DuplicateTagPath {
+        path: String,
+        tags: Vec<TagId>,
+    },
+    /// A live nested tag whose parent path has no live tag.
+    MissingParentTag {
+        tag: TagId,
+        parent_path: String,
+    },
+    /// A node references a tag id that does not exist at all.
+    DanglingTagRef {
+        node: NodeId,
+        tag: TagId,
+    },
+    /// A node was loaded with a date that could not be read. It was given
+    /// its other date instead, or the unix epoch if neither could be read.
+    UnrecognizedTimestamp {
+        node: NodeId,
+    },
+}
+
+/// How [`Repository::load_from_json_with`] handles integrity issues in the
+/// loaded records.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    serde::Serialize,
+    serde::Deserialize
+)]
+pub enum IntegrityMode {
+    /// Load the records as they are.
+    #[default]
+    Accept,
+    /// Fail with [`RepoError::Integrity`] listing the issues.
+    ///
+    /// [`RepoError::Integrity`]: crate::state::repository::RepoError::Integrity
+    Reject,
+    /// Fix the issues with [`Repository::repair`], as an undoable step.
+    Repair,
+}
+
+impl Repository {
+    /// Checks the node and tag records for referential integrity problems.
+    ///
+    /// Nodes referencing deleted tags are fine, the tags may be restored.
+    pub fn validate(&self) -> Vec<IntegrityIssue> {
+        let mut issues = Vec::new();
+
+        for (key, node) in &self.nodes {
+            if node.get_id() != key {
+                issues.push(IntegrityIssue::NodeIdMismatch { key: *key, record: *node.get_id() });
+            }
+            for tag in node.get_tags() {
+                if !self.tags.contains_key(tag) {
+                    issues.push(IntegrityIssue::DanglingTagRef { node: *key, tag: *tag });
+                }
+            }
+        }
+
+        let mut by_path: BTreeMap<String, Vec<TagId>> = BTreeMap::new();
+        for (key, tag) in &self.tags {
+            if tag.get_id() != key {
+                issues.push(IntegrityIssue::TagIdMismatch { key: *key, record: *tag.get_id() });
+            }
+            if tag.deleted {
+                continue;
+            }
+            if !is_valid_tag_path(tag.get_path()) {
+                issues.push(IntegrityIssue::InvalidTagPath { tag: *key });
+                continue;
+            }
+            by_path.entry(tag.get_path().join("/")).or_default().push(*key);
+        }
+
+        for (path, tags) in &by_path {
+            let segments = path.split('/').count();
+            if segments > 1 {
+                let parent_path = path.rsplit_once('/').map_or("", |(parent, _)| parent);
+                if !by_path.contains_key(parent_path) {
+                    for tag in tags {
+                        issues.push(IntegrityIssue::MissingParentTag {
+                            tag: *tag,
+                            parent_path: parent_path.to_string(),
+                        });
+                    }
+                }
+            }
+            if tags.len() > 1 {
+                let mut tags = tags.clone();
+                tags.sort();
+                issues.push(IntegrityIssue::DuplicateTagPath { path: path.clone(), tags });
+            }
+        }
+
+        for node in &self.unrecognized_timestamps {
+            if self.nodes.contains_key(node) {
+                issues.push(IntegrityIssue::UnrecognizedTimestamp { node: *node });
+            }
+        }
+
+        issues.sort();
+        issues
+    }
+
+    /// Fixes every problem [`validate`](Self::validate) reports and returns
+    /// the issues found before repairing.
+    ///
+    /// Records are re-keyed to their map key, invalid paths are normalized
+    /// (tags left without segments are deleted), duplicate tags are merged
+    /// into the lowest id, missing intermediate tags are created and dangling
+    /// tag references are dropped. Unreadable dates keep the fallback they
+    /// were loaded with. Indexes are rebuilt afterwards.
+    pub fn repair(&mut self) -> Vec<IntegrityIssue> {
+        let issues = self.validate();
+        if issues.is_empty() {
+            return issues;
+        }
+
+        let Ok(()) = self.logged(Operation::Repair, |repo| {
+            // repairs edit the maps directly, so diff them for merge stamps and undo
+            let nodes_before = repo.nodes.clone();
+            let tags_before = repo.tags.clone();
+            repo.repair_records();
+
+            let stamp = repo.stamp();
+            for (id, node) in repo.nodes.iter_mut() {
+                let old = nodes_before.get(id);
+                if old != Some(node) {
+                    stamp_node_against(old, node, stamp);
+                }
+            }
+            for (id, tag) in repo.tags.iter_mut() {
+                let old = tags_before.get(id);
+                if old != Some(tag) {
+                    stamp_tag_against(old, tag, stamp);
+                }
+            }
+
+            repo.history.record_diff(&nodes_before, &tags_before, &repo.nodes, &repo.tags);
+            Ok::<_, Infallible>(())
+        });
+        issues
+    }
+
+    fn repair_records(&mut self) {
+        for (key, node) in self.nodes.iter_mut() {
+            node.id = *key;
+        }
+        for (key, tag) in self.tags.iter_mut() {
+            tag.id = *key;
+        }
+
+        // the maps are edited directly, so the trash timestamp is set here
+        // for `purge_deleted_before` to pick the tags up
+        let now = self.now();
+        for tag in self.tags.values_mut().filter(|t| !t.deleted) {
+            if is_valid_tag_path(&tag.path) {
+                continue;
+            }
+            tag.path = tag.path
+                .iter()
+                .flat_map(|segment| segment.split('/'))
+                .filter(|segment| !segment.is_empty())
+                .map(|segment| segment.to_string())
+                .collect();
+            if tag.path.is_empty() {
+                tag.deleted = true;
+                tag.deleted_at = Some(now);
+            }
+        }
+
+        self.merge_duplicate_tags(now);
+        self.create_missing_parents();
+        self.unrecognized_timestamps.clear();
+
+        for node in self.nodes.values_mut() {
+            node.tags.retain(|tag| self.tags.contains_key(tag));
+        }
+
+        self.rebuild_all_indexes();
+    }
+
+    /// Deletes every live tag sharing its path with a lower id, moving its
+    /// nodes over to the lowest id. The merged tags are trashed at `now`.
+    fn merge_duplicate_tags(&mut self, now: DateTime<Utc>) {
+        let mut by_path: BTreeMap<Vec<String>, TagId> = BTreeMap::new();
+        let mut merged = BTreeMap::new();
+
+        let mut live: Vec<&TagRecord> = self.iter_tags().collect();
+        live.sort_by_key(|t| *t.get_id());
+        for tag in live {
+            match by_path.get(tag.get_path()) {
+                Some(keep) => {
+                    merged.insert(*tag.get_id(), *keep);
+                }
+                None => {
+                    by_path.insert(tag.get_path().clone(), *tag.get_id());
+                }
+            }
+        }
+
+        for (from, into) in &merged {
+            if let Some(tag) = self.tags.get_mut(from) {
+                tag.deleted = true;
+                tag.deleted_at = Some(now);
+            }
+            for node in self.nodes.values_mut().filter(|n| n.tags.contains(from)) {
+                node.tags.retain(|t| t != from);
+                if !node.tags.contains(into) {
+                    node.tags.push(*into);
+                }
+            }
+        }
+    }
+
+    /// Creates a tag for every missing ancestor path of a live tag.
+    fn create_missing_parents(&mut self) {
+        let mut paths: HashSet<Vec<String>> = self
+            .iter_tags()
+            .map(|t| t.get_path().clone())
+            .collect();
+
+        // ordered, so parents get lower ids than their children
+        let mut missing: BTreeSet<Vec<String>> = BTreeSet::new();
+        for path in &paths {
+            for len in 1..path.len() {
+                let prefix = &path[..len];
+                if !paths.contains(prefix) {
+                    missing.insert(prefix.to_vec());
+                }
+            }
+        }
+
+        for path in missing {
+            let id = self.get_next_tag_id();
+            paths.insert(path.clone());
+            let mut tag = TagRecord::new(id, path, None);
+            tag.uid = Uid::derived(self.stamp(), UidKind::Tag, id.0);
+            self.tags.insert(id, tag);
+            self.next_tag_id.0 = id.0 + 1;
+        }
+    }
+}
@@ -0,0 +1,419 @@
+//! Conflict-free merging of repositories edited on several devices.
+//!
+//! Every record carries a clock (see [`NodeClock`] and
+//! [`TagClock`](crate::tag::TagClock)) that the repository stamps with a
+//! hybrid logical clock whenever a field changes.
+//! Merging keeps the latest write of each field and the union of tag
+//! additions minus the removed ones, so repositories that have merged each
+//! other's changes end up with the same records in any order.
+
+use std::{ cmp::Ordering, collections::{ BTreeSet, HashMap }, convert::Infallible, hash::Hash };
+
+use chrono::{ DateTime, Utc };
+
+use crate::{
+    hlc::Hlc,
+    node::{ NodeClock, NodeId, NodeRecord },
+    state::{ oplog::Operation, repository::Repository },
+    tag::{ TagId, TagRecord },
+    uid::Uid,
+};
+
+/// Records changed by [`Repository::merge`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct MergeReport {
+    pub nodes: Vec<NodeId>,
+    pub tags: Vec<TagId>,
+}
+
+impl Repository {
+    /// Merges the records of `other` into this repository.
+    ///
+    /// Records are matched by [`Uid`]; records new to this repository get
+    /// fresh local ids. Node data, deletion state and tag paths, colors and
+    /// deletion state follow the latest write; a tag added to a node on one
+    /// side and concurrently removed on the other stays. Deleted records merge
+    /// like any other state, but records removed physically (by emptying the
+    /// trash, purging or undoing their creation) come back if `other` still
+    /// has them.
+    ///
+    /// Stamps of concurrent writes are ordered by actor, so every device
+    /// needs its own name; new repositories get a random one, see
+    /// [`set_actor`](Self::set_actor). Writes with equal stamps keep the
+    /// larger value. The merge is logged and undoable.
+    pub fn merge(&mut self, other: &Repository) -> MergeReport {
+        let mut next_tag = self.next_tag_id.0;
+        let tag_ids = local_ids(
+            other.tags
+                .iter()
+                .map(|(id, tag)| (*id, Uid::of_tag(*id, tag)))
+                .collect(),
+            &self.uids.tags,
+            || {
+                while self.tags.contains_key(&TagId(next_tag)) {
+                    next_tag += 1;
+                }
+                next_tag += 1;
+                TagId(next_tag - 1)
+            }
+        );
+        let mut next_node = self.next_node_id.0;
+        let node_ids = local_ids(
+            other.nodes
+                .iter()
+                .map(|(id, node)| (*id, Uid::of_node(*id, node)))
+                .collect(),
+            &self.uids.nodes,
+            || {
+                while self.nodes.contains_key(&NodeId(next_node)) {
+                    next_node += 1;
+                }
+                next_node += 1;
+                NodeId(next_node - 1)
+            }
+        );
+
+        let mut tags: Vec<TagRecord> = other.tags
+            .iter()
+            .filter_map(|(id, theirs)| {
+                let mut theirs = theirs.clone();
+                (theirs.id, theirs.uid) = tag_ids[id];
+
+                match self.tags.get(&theirs.id) {
+                    Some(ours) => {
+                        let merged = merge_tags(ours, &theirs);
+                        (merged != *ours).then_some(merged)
+                    }
+                    None => Some(theirs),
+                }
+            })
+            .collect();
+        let mut nodes: Vec<NodeRecord> = other.nodes
+            .iter()
+            .filter_map(|(id, theirs)| {
+                let mut theirs = translate_node(theirs, &tag_ids);
+                (theirs.id, theirs.uid) = node_ids[id];
+
+                match self.nodes.get(&theirs.id) {
+                    Some(ours) => {
+                        let merged = merge_nodes(ours, &theirs);
+                        (merged != *ours).then_some(merged)
+                    }
+                    None => Some(theirs),
+                }
+            })
+            .collect();
+        tags.sort_by_key(|t| t.id);
+        nodes.sort_by_key(|n| n.id);
+
+        let report = MergeReport {
+            nodes: nodes
+                .iter()
+                .map(|n| n.id)
+                .collect(),
+            tags: tags
+                .iter()
+                .map(|t| t.id)
+                .collect(),
+        };
+        if nodes.is_empty() && tags.is_empty() {
+            return report;
+        }
+
+        let op = Operation::Merge { nodes: nodes.clone(), tags: tags.clone() };
+        let Ok(()) = self.logged(op, |repo| {
+            repo.apply_merge(nodes, tags);
+            Ok::<_, Infallible>(())
+        });
+        report
+    }
+
+    /// Stores merged records as they are, advancing the clock past their
+    /// stamps so later local writes win over them.
+    pub(crate) fn apply_merge(&mut self, nodes: Vec<NodeRecord>, tags: Vec<TagRecord>) {
+        for tag in tags {
+            self.observe(tag_stamps(&tag));
+            self.next_tag_id = self.next_tag_id.max(TagId(tag.id.0 + 1));
+            self.put_tag(tag.id, Some(tag));
+        }
+        for node in nodes {
+            self.observe(node_stamps(&node));
+            self.next_node_id = self.next_node_id.max(NodeId(node.id.0 + 1));
+            self.put_node(node.id, Some(node));
+        }
+    }
+
+    /// Drops the tag removals recorded before `cutoff` from every node and
+    /// returns how many were dropped.
+    ///
+    /// Removals are kept so that a merge does not bring back a tag another
+    /// device still has as added. Once every device has merged the changes
+    /// made before `cutoff` they are no longer needed; a device that has not
+    /// may bring back tags removed before then.
+    pub fn compact_tombstones(&mut self, cutoff: DateTime<Utc>) -> usize {
+        let cutoff_millis = cutoff.timestamp_millis();
+        let mut compacted: Vec<NodeRecord> = Vec::new();
+        let mut dropped = 0;
+        for node in self.nodes.values() {
+            let kept = node.clock.tag_removes
+                .iter()
+                .filter(|(_, stamp)| stamp.millis >= cutoff_millis)
+                .count();
+            if kept < node.clock.tag_removes.len() {
+                dropped += node.clock.tag_removes.len() - kept;
+                let mut node = node.clone();
+                node.clock.tag_removes.retain(|(_, stamp)| stamp.millis >= cutoff_millis);
+                compacted.push(node);
+            }
+        }
+        if compacted.is_empty() {
+            return 0;
+        }
+        compacted.sort_by_key(|n| n.id);
+
+        // stored as they are: the removals are merge bookkeeping, not an edit
+        let Ok(()) = self.logged(Operation::CompactTombstones(cutoff), |repo| {
+            for node in compacted {
+                repo.put_node(node.id, Some(node));
+            }
+            Ok::<_, Infallible>(())
+        });
+        dropped
+    }
+
+    /// The stamp of the running operation, issued on first use, so the
+    /// stamps do not depend on the order the operation writes records in.
+    pub(crate) fn stamp(&mut self) -> Hlc {
+        if let Some(stamp) = self.operation_stamp {
+            return stamp;
+        }
+
+        let millis = self.now().timestamp_millis();
+        self.last_stamp = Hlc::next(self.last_stamp, millis, self.oplog.replica());
+        if self.oplog.in_operation() {
+            self.operation_stamp = Some(self.last_stamp);
+        }
+        self.last_stamp
+    }
+
+    fn observe(&mut self, stamps: impl IntoIterator<Item = Hlc>) {
+        for stamp in stamps {
+            self.last_stamp = self.last_stamp.max(stamp);
+        }
+    }
+
+    /// The latest stamp in any record, for repositories loaded from disk.
+    pub(crate) fn latest_stamp(&self) -> Hlc {
+        let tags = self.tags.values().flat_map(tag_stamps);
+        let nodes = self.nodes.values().flat_map(node_stamps);
+        tags.chain(nodes).max().unwrap_or_default()
+    }
+
+    /// Stamps the fields of `node` that differ from the stored record.
+    pub(crate) fn stamp_node(&mut self, node: &mut NodeRecord) {
+        let stamp = self.stamp();
+        stamp_node_against(self.nodes.get(&node.id), node, stamp);
+    }
+
+    /// Stamps the fields of `tag` that differ from the stored record.
+    pub(crate) fn stamp_tag(&mut self, tag: &mut TagRecord) {
+        let stamp = self.stamp();
+        stamp_tag_against(self.tags.get(&tag.id), tag, stamp);
+    }
+}
+
+/// Derives the clock of `node` from the clock of the record it replaces,
+/// stamping changed fields with `stamp`. Tags are put in the order they were
+/// first added.
+pub(crate) fn stamp_node_against(old: Option<&NodeRecord>, node: &mut NodeRecord, stamp: Hlc) {
+    let mut clock = match old {
+        Some(old) => NodeClock {
+            tag_adds: old.clock.effective_adds(&old.tags),
+            ..old.clock.clone()
+        },
+        None => NodeClock::default(),
+    };
+
+    if old.is_none_or(|old| old.data_ref != node.data_ref) {
+        clock.data = stamp;
+    }
+    if old.is_none_or(|old| old.deleted != node.deleted) {
+        clock.deleted = stamp;
+    }
+
+    let (kept, removed): (Vec<_>, Vec<_>) = clock.tag_adds
+        .into_iter()
+        .partition(|(tag, _)| node.tags.contains(tag));
+    clock.tag_adds = kept;
+    clock.tag_removes.extend(removed);
+    for tag in &node.tags {
+        if !clock.tag_adds.iter().any(|(t, _)| t == tag) {
+            // re-added by the same operation that removed it
+            clock.tag_removes.retain(|dot| *dot != (*tag, stamp));
+            clock.tag_adds.push((*tag, stamp));
+        }
+    }
+    sort_dots(&mut clock.tag_adds);
+    sort_dots(&mut clock.tag_removes);
+
+    node.tags = clock.live_tags();
+    node.clock = clock;
+}
+
+/// Derives the clock of `tag` from the clock of the record it replaces,
+/// stamping changed fields with `stamp`.
+pub(crate) fn stamp_tag_against(old: Option<&TagRecord>, tag: &mut TagRecord, stamp: Hlc) {
+    let mut clock = old.map(|old| old.clock.clone()).unwrap_or_default();
+
+    if old.is_none_or(|old| old.path != tag.path) {
+        clock.path = stamp;
+    }
+    if old.is_none_or(|old| old.color != tag.color) {
+        clock.color = stamp;
+    }
+    if old.is_none_or(|old| old.deleted != tag.deleted) {
+        clock.deleted = stamp;
+    }
+
+    tag.clock = clock;
+}
+
+/// Maps the ids of other records to local ids and uids: the id of the local
+/// record with the same uid, or a fresh one.
+///
+/// Records sharing a uid with a lower id get a [`distinct`](Uid::distinct)
+/// uid instead of being matched to the same local record, where the later
+/// one would overwrite the earlier.
+fn local_ids<Id: Copy + Ord + Hash>(
+    mut records: Vec<(Id, Uid)>,
+    local: &HashMap<Uid, Id>,
+    mut fresh: impl FnMut() -> Id
+) -> HashMap<Id, (Id, Uid)> {
+    // fresh ids in id order, so the result does not depend on map order
+    records.sort();
+    let mut seen: HashMap<Uid, u32> = HashMap::new();
+    records
+        .into_iter()
+        .map(|(id, uid)| {
+            let count = seen.entry(uid).or_default();
+            let uid = if *count == 0 { uid } else { uid.distinct(*count) };
+            *count += 1;
+            (id, (local.get(&uid).copied().unwrap_or_else(&mut fresh), uid))
+        })
+        .collect()
+}
+
+/// A node of another repository with its tag references mapped to local ids.
+/// References to tags the other repository does not have are dropped.
+fn translate_node(node: &NodeRecord, tag_ids: &HashMap<TagId, (TagId, Uid)>) -> NodeRecord {
+    let translate = |dots: &[(TagId, Hlc)]| {
+        let mut dots: Vec<(TagId, Hlc)> = dots
+            .iter()
+            .filter_map(|(tag, stamp)| Some((tag_ids.get(tag)?.0, *stamp)))
+            .collect();
+        sort_dots(&mut dots);
+        dots
+    };
+
+    let mut node = node.clone();
+    node.tags = node.tags
+        .iter()
+        .filter_map(|tag| Some(tag_ids.get(tag)?.0))
+        .collect();
+    node.clock.tag_adds = translate(&node.clock.tag_adds);
+    node.clock.tag_removes = translate(&node.clock.tag_removes);
+    node
+}
+
+fn merge_nodes(ours: &NodeRecord, theirs: &NodeRecord) -> NodeRecord {
+    let mut merged = ours.clone();
+    merged.uid = theirs.uid;
+
+    if theirs_wins((ours.clock.data, &ours.data_ref), (theirs.clock.data, &theirs.data_ref)) {
+        merged.data_ref = theirs.data_ref.clone();
+        merged.clock.data = theirs.clock.data;
+    }
+    let deletion = |node: &NodeRecord| (node.clock.deleted, (node.deleted, node.deleted_at));
+    if theirs_wins(deletion(ours), deletion(theirs)) {
+        merged.deleted = theirs.deleted;
+        merged.deleted_at = theirs.deleted_at;
+        merged.clock.deleted = theirs.clock.deleted;
+    }
+    merged.date_created = ours.date_created.min(theirs.date_created);
+    merged.date_updated = ours.date_updated.max(theirs.date_updated);
+
+    let removes: BTreeSet<(TagId, Hlc)> = ours.clock.tag_removes
+        .iter()
+        .chain(&theirs.clock.tag_removes)
+        .copied()
+        .collect();
+    let mut adds: Vec<(TagId, Hlc)> = ours.clock
+        .effective_adds(&ours.tags)
+        .into_iter()
+        .chain(theirs.clock.effective_adds(&theirs.tags))
+        .filter(|dot| !removes.contains(dot))
+        .collect();
+    sort_dots(&mut adds);
+
+    merged.clock.tag_adds = adds;
+    merged.clock.tag_removes = removes.into_iter().collect();
+    sort_dots(&mut merged.clock.tag_removes);
+    merged.tags = merged.clock.live_tags();
+    merged
+}
+
+fn merge_tags(ours: &TagRecord, theirs: &TagRecord) -> TagRecord {
+    let mut merged = ours.clone();
+    merged.uid = theirs.uid;
+
+    if theirs_wins((ours.clock.path, &ours.path), (theirs.clock.path, &theirs.path)) {
+        merged.path = theirs.path.clone();
+        merged.clock.path = theirs.clock.path;
+    }
+    if theirs_wins((ours.clock.color, &ours.color), (theirs.clock.color, &theirs.color)) {
+        merged.color = theirs.color;
+        merged.clock.color = theirs.clock.color;
+    }
+    let deletion = |tag: &TagRecord| (tag.clock.deleted, (tag.deleted, tag.deleted_at));
+    if theirs_wins(deletion(ours), deletion(theirs)) {
+        merged.deleted = theirs.deleted;
+        merged.deleted_at = theirs.deleted_at;
+        merged.clock.deleted = theirs.clock.deleted;
+    }
+    merged
+}
+
+/// Whether their write of a field replaces ours: the later stamp wins.
+/// Equal stamps come from devices sharing an actor name or from records
+/// predating stamps; the larger serialized value wins then, so merging does
+/// not depend on which side is ours.
+fn theirs_wins<T: serde::Serialize>(ours: (Hlc, T), theirs: (Hlc, T)) -> bool {
+    match theirs.0.cmp(&ours.0) {
+        Ordering::Greater => true,
+        Ordering::Less => false,
+        Ordering::Equal => tie_key(&theirs.1) > tie_key(&ours.1),
+    }
+}
+
+fn tie_key<T: serde::Serialize>(value: &T) -> Vec<u8> {
+    serde_json::to_vec(value).unwrap_or_default()
+}
+
+/// Orders tag additions or removals by stamp, dropping duplicates.
+fn sort_dots(dots: &mut Vec<(TagId, Hlc)>) {
+    dots.sort_by_key(|(tag, stamp)| (*stamp, *tag));
+    dots.dedup();
+}
+
+fn node_stamps(node: &NodeRecord) -> impl Iterator<Item = Hlc> + '_ {
+    let clock = &node.clock;
+    let dots = clock.tag_adds
+        .iter()
+        .chain(&clock.tag_removes)
+        .map(|(_, stamp)| *stamp);
+    [clock.data, clock.deleted].into_iter().chain(dots)
+}
+
+fn tag_stamps(tag: &TagRecord) -> [Hlc; 3] {
+    [tag.clock.path, tag.clock.color, tag.clock.deleted]
+}
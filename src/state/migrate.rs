@@ -0,0 +1,182 @@
+use std::{ collections::HashMap, convert::Infallible };
+
+use crate::{
+    blob::{ BlobParams, BlobStore, DataBlob, EncryptionKey },
+    node::NodeId,
+    state::{ oplog::Operation, repository::Repository },
+};
+
+/// Rewrites completed so far by [`Repository::migrate_blobs`].
+///
+/// Persist this between runs to resume an interrupted migration; nodes whose
+/// data blob still matches `source` are not rewritten again.
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct MigrationProgress {
+    pub migrated: HashMap<NodeId, MigratedBlob>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct MigratedBlob {
+    pub source: DataBlob,
+    pub target: DataBlob,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct MigrationFailure {
+    pub node: NodeId,
+    pub error: String,
+}
+
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct MigrationReport {
+    /// Nodes rewritten during this run.
+    pub migrated: usize,
+
+    /// Nodes taken over from a previous run.
+    pub resumed: usize,
+
+    /// Distinct blobs written and read back intact from the destination.
+    pub blobs_verified: usize,
+
+    /// Logical bytes written to the destination during this run.
+    pub bytes_written: u64,
+
+    pub failures: Vec<MigrationFailure>,
+
+    /// Whether the repository now references the destination blobs. Only
+    /// happens once every node migrated without failure.
+    pub applied: bool,
+}
+
+impl Repository {
+    /// Rewrites the data of every node (including deleted ones) from `source`
+    /// into `destination` using `params`, verifying each copy by reading it
+    /// back. `source_key` decrypts source blobs that were encrypted; changing
+    /// the chunk size, compression, key or hash algorithm is all a matter of
+    /// `params`.
+    ///
+    /// Node references are switched to the new blobs all at once, and only if
+    /// no node failed; `progress` is cleared then. Otherwise the repository is
+    /// left untouched and `progress` holds the completed rewrites for the next
+    /// attempt. The data itself is unchanged, so the switch keeps each node's
+    /// dates and merge stamps.
+    pub fn migrate_blobs<S: BlobStore, D: BlobStore>(
+        &mut self,
+        source: &S,
+        source_key: Option<&EncryptionKey>,
+        destination: &mut D,
+        params: &BlobParams,
+        progress: &mut MigrationProgress
+    ) -> MigrationReport
+        where <S as BlobStore>::Error: std::fmt::Debug, <D as BlobStore>::Error: std::fmt::Debug
+    {
+        let mut report = MigrationReport::default();
+
+        // nodes often share data; rewrite each source blob only once
+        let mut rewritten: HashMap<DataBlob, DataBlob> = progress.migrated
+            .values()
+            .map(|m| (m.source.clone(), m.target.clone()))
+            .collect();
+
+        let mut node_ids: Vec<NodeId> = self.nodes.keys().copied().collect();
+        node_ids.sort_by_key(|id| id.0);
+
+        for node_id in node_ids {
+            let source_blob = self.nodes[&node_id].data_ref.get_data_blob().clone();
+
+            let already_done = progress.migrated
+                .get(&node_id)
+                .is_some_and(|done| done.source == source_blob);
+            if already_done {
+                report.resumed += 1;
+                continue;
+            }
+
+            let target = match rewritten.get(&source_blob) {
+                Some(target) => target.clone(),
+                None => {
+                    let result = Self::migrate_blob(
+                        source,
+                        source_key,
+                        destination,
+                        params,
+                        &source_blob
+                    );
+                    match result {
+                        Ok((target, size)) => {
+                            report.bytes_written += size;
+                            report.blobs_verified += 1;
+                            rewritten.insert(source_blob.clone(), target.clone());
+                            target
+                        }
+                        Err(error) => {
+                            report.failures.push(MigrationFailure { node: node_id, error });
+                            continue;
+                        }
+                    }
+                }
+            };
+
+            report.migrated += 1;
+            progress.migrated.insert(node_id, MigratedBlob {
+                source: source_blob,
+                target,
+            });
+        }
+
+        if report.failures.is_empty() {
+            let mut blobs: Vec<(NodeId, DataBlob)> = progress.migrated
+                .drain()
+                .map(|(node_id, migrated)| (node_id, migrated.target))
+                .collect();
+            blobs.sort_by_key(|(node_id, _)| *node_id);
+            self.switch_data_blobs(blobs);
+            report.applied = true;
+        }
+
+        report
+    }
+
+    /// Points nodes at their migrated data blobs, as one logged
+    /// [`Operation::MigrateBlobs`]. The records are stored without going
+    /// through `write_node`, which would bump their dates and stamps.
+    pub(crate) fn switch_data_blobs(&mut self, blobs: Vec<(NodeId, DataBlob)>) {
+        let Ok(()) = self.logged(Operation::MigrateBlobs(blobs.clone()), |repo| {
+            for (node_id, blob) in blobs {
+                let Some(mut node) = repo.nodes.get(&node_id).cloned() else {
+                    continue;
+                };
+                node.data_ref.set_data_blob(blob);
+                repo.put_node(node_id, Some(node));
+            }
+            Ok::<_, Infallible>(())
+        });
+    }
+
+    fn migrate_blob<S: BlobStore, D: BlobStore>(
+        source: &S,
+        source_key: Option<&EncryptionKey>,
+        destination: &mut D,
+        params: &BlobParams,
+        blob: &DataBlob
+    ) -> Result<(DataBlob, u64), String>
+        where <S as BlobStore>::Error: std::fmt::Debug, <D as BlobStore>::Error: std::fmt::Debug
+    {
+        let data = blob
+            .retrieve_data_with_key(source, source_key)
+            .map_err(|e| format!("read: {:?}", e))?;
+
+        let target = DataBlob::from_data_with_params(destination, &data, params).map_err(|e|
+            format!("write: {:?}", e)
+        )?;
+
+        let written = target
+            .retrieve_data_with_key(destination, params.encryption.as_ref())
+            .map_err(|e| format!("verify: {:?}", e))?;
+        if written != data {
+            return Err("verify: data mismatch".to_string());
+        }
+
+        Ok((target, data.len() as u64))
+    }
+}
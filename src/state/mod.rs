@@ -1 +1,15 @@
 pub mod repository;
+pub mod accounting;
+pub mod diff;
+pub mod events;
+pub mod history;
+pub mod migrate;
+pub mod oplog;
+pub mod ingest;
+pub mod integrity;
+pub mod merge;
+pub mod query;
+pub mod snapshot;
+pub mod three_way;
+pub mod transaction;
+pub mod trash;
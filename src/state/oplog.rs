@@ -0,0 +1,430 @@
+//! Append-only log of repository mutations.
+//!
+//! Every public mutation appends one [`LoggedOperation`]. Mutations built
+//! from other mutations (a tag deletion that re-parents children, say) are
+//! logged once, as the outer call. Operations replay deterministically: the
+//! repository's clock is pinned to the logged timestamp while one is applied.
+
+use chacha20poly1305::aead::{ OsRng, rand_core::RngCore };
+use chrono::{ DateTime, Utc };
+
+use crate::{
+    blob::DataBlob,
+    hlc::Hlc,
+    node::{ NodeId, NodeRecord },
+    state::{
+        diff::RepoDiff,
+        repository::{ DeleteTagOptions, RepoError, Repository, SetTagPathOptions },
+    },
+    tag::{ TagColors, TagId, TagRecord },
+};
+
+/// Batches writing at least one record per this many records in the
+/// repository rebuild its indexes instead of updating them record by record.
+const BATCH_REBUILD_SHARE: usize = 8;
+
+/// A single mutation, as recorded in the [`OpLog`].
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub enum Operation {
+    UpsertNode(NodeRecord),
+    DeleteNode(NodeId),
+    RestoreNode(NodeId),
+    TagNode {
+        node: NodeId,
+        tag: TagId,
+    },
+    UntagNode {
+        node: NodeId,
+        tag: TagId,
+    },
+    UpsertTag(TagRecord),
+    DeleteTag {
+        tag: TagId,
+        options: DeleteTagOptions,
+    },
+    RestoreTag(TagId),
+    EnsureTagPath {
+        path: Vec<String>,
+        color: Option<TagColors>,
+    },
+    SetTagPath {
+        tag: TagId,
+        path: Vec<String>,
+        options: SetTagPathOptions,
+    },
+    EmptyTrash,
+    PurgeDeletedBefore(DateTime<Utc>),
+    CompactTombstones(DateTime<Utc>),
+    Repair,
+    /// Records written as a unit, as transactions and blob migrations were
+    /// logged before they had operations of their own.
+    Batch {
+        nodes: Vec<NodeRecord>,
+        tags: Vec<TagRecord>,
+    },
+    /// Records put back as given by undo, redo or a snapshot rollback;
+    /// `None` removes the record.
+    Revert {
+        nodes: Vec<(NodeId, Option<NodeRecord>)>,
+        tags: Vec<(TagId, Option<TagRecord>)>,
+    },
+    /// Records changed by [`Repository::merge`], stored as they are.
+    Merge {
+        nodes: Vec<NodeRecord>,
+        tags: Vec<TagRecord>,
+    },
+    Patch(RepoDiff),
+    /// Data blobs switched by [`Repository::migrate_blobs`]; dates and merge
+    /// stamps stay as they are.
+    MigrateBlobs(Vec<(NodeId, DataBlob)>),
+    /// Operations run by [`Repository::transaction`], applied in order as
+    /// one unit.
+    Transaction(Vec<Operation>),
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct LoggedOperation {
+    /// Position in the log, starting at 1.
+    pub seq: u64,
+    pub actor: String,
+    pub timestamp: DateTime<Utc>,
+    pub op: Operation,
+}
+
+#[derive(Clone, Debug)]
+pub struct OpLog {
+    entries: Vec<LoggedOperation>,
+    actor: String,
+
+    /// [`Hlc`] replica id of `actor`.
+    replica: u64,
+
+    /// Nesting depth of logged calls; only the outermost one is recorded.
+    depth: usize,
+}
+
+impl Default for OpLog {
+    fn default() -> Self {
+        let actor = random_actor();
+        Self {
+            entries: Vec::new(),
+            replica: Hlc::replica_of(&actor),
+            actor,
+            depth: 0,
+        }
+    }
+}
+
+/// The actor every repository had before each got its own. A log saved with
+/// it is given a fresh one on load, as it would share its replica id with
+/// every other such log.
+const SHARED_DEFAULT_ACTOR: &str = "local";
+
+/// A name no other repository is expected to pick, so devices that never
+/// call [`Repository::set_actor`] still write with distinct replica ids.
+fn random_actor() -> String {
+    format!("{:016x}", OsRng.next_u64())
+}
+
+/// What is saved of an [`OpLog`]; the replica id follows from the actor.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct OpLogSerde {
+    entries: Vec<LoggedOperation>,
+    actor: String,
+}
+
+impl serde::Serialize for OpLog {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: serde::Serializer {
+        let saved = OpLogSerde { entries: self.entries.clone(), actor: self.actor.clone() };
+        saved.serialize(serializer)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for OpLog {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: serde::Deserializer<'de> {
+        let saved = OpLogSerde::deserialize(deserializer)?;
+        let mut oplog = OpLog { entries: saved.entries, ..OpLog::default() };
+        if saved.actor != SHARED_DEFAULT_ACTOR {
+            oplog.set_actor(saved.actor);
+        }
+        Ok(oplog)
+    }
+}
+
+impl OpLog {
+    pub fn entries(&self) -> &[LoggedOperation] {
+        &self.entries
+    }
+
+    /// Entries after `seq`, e.g. to persist or sync only what is new.
+    pub fn entries_since(&self, seq: u64) -> &[LoggedOperation] {
+        let start = self.entries.partition_point(|entry| entry.seq <= seq);
+        &self.entries[start..]
+    }
+
+    /// Sequence number of the last entry, or `0` for an empty log.
+    pub fn last_seq(&self) -> u64 {
+        self.entries.last().map_or(0, |entry| entry.seq)
+    }
+
+    pub fn actor(&self) -> &str {
+        &self.actor
+    }
+
+    pub(crate) fn replica(&self) -> u64 {
+        self.replica
+    }
+
+    pub(crate) fn in_operation(&self) -> bool {
+        self.depth > 0
+    }
+
+    fn set_actor(&mut self, actor: String) {
+        self.replica = Hlc::replica_of(&actor);
+        self.actor = actor;
+    }
+
+    /// Drops the entries up to and including `seq`, once they are covered by
+    /// a [`Checkpoint`].
+    pub fn truncate_through(&mut self, seq: u64) {
+        let end = self.entries.partition_point(|entry| entry.seq <= seq);
+        self.entries.drain(..end);
+    }
+}
+
+/// The repository's records as of a log position. Replaying the log entries
+/// after `seq` on top of it reproduces the repository.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Checkpoint {
+    pub seq: u64,
+    pub repository: Repository,
+}
+
+impl Repository {
+    pub fn oplog(&self) -> &OpLog {
+        &self.oplog
+    }
+
+    pub fn oplog_mut(&mut self) -> &mut OpLog {
+        &mut self.oplog
+    }
+
+    /// Sets who is recorded as the author of subsequent operations. The name
+    /// also identifies this device's writes when merging, see
+    /// [`merge`](Self::merge).
+    ///
+    /// A new repository starts with a random name, and a loaded one keeps
+    /// the name it was saved with. A copy of a saved repository opened on
+    /// another device should be given a name of its own.
+    pub fn set_actor(&mut self, actor: impl Into<String>) {
+        self.oplog.set_actor(actor.into());
+    }
+
+    /// Runs a mutation, recording `op` if it succeeds and is not nested in
+    /// another logged mutation. See [`run_operation`](Self::run_operation).
+    pub(crate) fn logged<T, E>(
+        &mut self,
+        op: Operation,
+        f: impl FnOnce(&mut Self) -> Result<T, E>
+    ) -> Result<T, E> {
+        self.logged_with(|repo| f(repo).map(|value| (value, op)))
+    }
+
+    /// Like [`logged`](Self::logged), for mutations that only know the
+    /// operation to record once they have run.
+    pub(crate) fn logged_with<T, E>(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<(T, Operation), E>
+    ) -> Result<T, E> {
+        let timestamp = self.now();
+        let (value, op) = self.run_operation(timestamp, f)?;
+
+        if self.oplog.depth == 0 {
+            let entry = LoggedOperation {
+                seq: self.oplog.last_seq() + 1,
+                actor: self.oplog.actor.clone(),
+                timestamp,
+                op,
+            };
+            self.oplog.entries.push(entry);
+        }
+
+        Ok(value)
+    }
+
+    /// Runs a mutation with the clock pinned to `timestamp`, so all records
+    /// written by one operation share it. A failed mutation is rolled back,
+    /// id counters and merge stamps included; a successful outermost one
+    /// notifies subscribers and becomes an undo step.
+    fn run_operation<T, E>(
+        &mut self,
+        timestamp: DateTime<Utc>,
+        f: impl FnOnce(&mut Self) -> Result<T, E>
+    ) -> Result<T, E> {
+        let pinned = self.clock.replace(timestamp);
+        let stamps = (self.last_stamp, self.operation_stamp);
+        let next_ids = (self.next_node_id, self.next_tag_id);
+        let mark = self.history.mark();
+        self.oplog.depth += 1;
+
+        let result = f(self);
+
+        self.oplog.depth -= 1;
+        self.clock = pinned;
+
+        if result.is_err() {
+            self.roll_back_to(mark);
+            (self.last_stamp, self.operation_stamp) = stamps;
+            (self.next_node_id, self.next_tag_id) = next_ids;
+        } else if self.oplog.depth == 0 {
+            self.subscribers.notify(self.history.changes_since(mark), &self.nodes, &self.tags);
+            self.history.commit();
+        }
+        if self.oplog.depth == 0 {
+            self.operation_stamp = None;
+        }
+
+        result
+    }
+
+    /// Writes records as one logged [`Operation::Batch`].
+    ///
+    /// A batch covering a sizable share of the repository stores all records
+    /// first and rebuilds the indexes once, instead of updating them per
+    /// record; smaller ones are cheaper to index incrementally.
+    pub(crate) fn write_batch(&mut self, nodes: Vec<NodeRecord>, tags: Vec<TagRecord>) {
+        let op = Operation::Batch { nodes: nodes.clone(), tags: tags.clone() };
+        let Ok(()) = self.logged(op, |repo| {
+            let size = nodes.len() + tags.len();
+            let defer = size * BATCH_REBUILD_SHARE >= repo.nodes.len() + repo.tags.len();
+            let deferred = std::mem::replace(&mut repo.indexes_deferred, defer);
+
+            // tags first, so the hierarchy is in place when nodes are indexed
+            for tag in tags {
+                repo.next_tag_id = repo.next_tag_id.max(TagId(tag.get_id().0 + 1));
+                repo.write_tag(tag);
+            }
+            for node in nodes {
+                repo.next_node_id = repo.next_node_id.max(NodeId(node.get_id().0 + 1));
+                repo.write_node(node);
+            }
+
+            repo.indexes_deferred = deferred;
+            if defer && !deferred {
+                repo.rebuild_all_indexes();
+            }
+            Ok::<_, std::convert::Infallible>(())
+        });
+    }
+
+    /// Builds a repository by replaying `entries` from an empty state.
+    pub fn replay(entries: &[LoggedOperation]) -> Result<Repository, RepoError> {
+        let mut repo = Repository::new();
+        for entry in entries {
+            repo.apply_logged(entry)?;
+        }
+        Ok(repo)
+    }
+
+    /// Builds a repository from a checkpoint and the log entries after it.
+    /// Entries already covered by the checkpoint are skipped.
+    pub fn from_checkpoint(
+        checkpoint: Checkpoint,
+        tail: &[LoggedOperation]
+    ) -> Result<Repository, RepoError> {
+        let mut repo = checkpoint.repository;
+        repo.finish_load();
+        for entry in tail.iter().filter(|entry| entry.seq > checkpoint.seq) {
+            repo.apply_logged(entry)?;
+        }
+        Ok(repo)
+    }
+
+    /// Captures the current records at the end of the log.
+    pub fn checkpoint(&self) -> Checkpoint {
+        let mut repository = self.clone();
+        repository.oplog = OpLog::default();
+        Checkpoint { seq: self.oplog.last_seq(), repository }
+    }
+
+    /// Applies a logged operation as its original author did, at its original
+    /// time, and appends it to this repository's log unchanged.
+    pub fn apply_logged(&mut self, entry: &LoggedOperation) -> Result<(), RepoError> {
+        let actor = self.oplog.actor.clone();
+        self.oplog.set_actor(entry.actor.clone());
+        let result = self.run_operation(entry.timestamp, |repo| {
+            repo.apply_operation(entry.op.clone())
+        });
+        self.oplog.set_actor(actor);
+
+        result?;
+        self.oplog.entries.push(entry.clone());
+        Ok(())
+    }
+
+    fn apply_operation(&mut self, op: Operation) -> Result<(), RepoError> {
+        match op {
+            Operation::UpsertNode(node) => self.upsert_node(node).map(|_| ()),
+            Operation::DeleteNode(node) => self.delete_node(node),
+            Operation::RestoreNode(node) => self.restore_node(node),
+            Operation::TagNode { node, tag } => self.tag_node(node, tag),
+            Operation::UntagNode { node, tag } => self.untag_node(node, tag),
+            Operation::UpsertTag(tag) => self.upsert_tag(tag).map(|_| ()),
+            Operation::DeleteTag { tag, options } => self.delete_tag_with(tag, options),
+            Operation::RestoreTag(tag) => self.restore_tag(tag),
+            Operation::EnsureTagPath { path, color } => {
+                let path: Vec<&str> = path
+                    .iter()
+                    .map(|s| s.as_str())
+                    .collect();
+                self.ensure_tag_path(&path, color).map(|_| ())
+            }
+            Operation::SetTagPath { tag, path, options } => {
+                let path = path
+                    .iter()
+                    .map(|s| s.as_str())
+                    .collect();
+                self.set_tag_path_with(tag, path, options)
+            }
+            Operation::EmptyTrash => {
+                self.empty_trash();
+                Ok(())
+            }
+            Operation::PurgeDeletedBefore(cutoff) => {
+                self.purge_deleted_before(cutoff);
+                Ok(())
+            }
+            Operation::CompactTombstones(cutoff) => {
+                self.compact_tombstones(cutoff);
+                Ok(())
+            }
+            Operation::Repair => {
+                self.repair();
+                Ok(())
+            }
+            Operation::Batch { nodes, tags } => {
+                self.write_batch(nodes, tags);
+                Ok(())
+            }
+            Operation::Revert { nodes, tags } => {
+                self.revert(nodes, tags);
+                Ok(())
+            }
+            Operation::Merge { nodes, tags } => {
+                self.apply_merge(nodes, tags);
+                Ok(())
+            }
+            Operation::Patch(diff) => self.apply_diff(&diff),
+            Operation::MigrateBlobs(blobs) => {
+                self.switch_data_blobs(blobs);
+                Ok(())
+            }
+            Operation::Transaction(ops) => {
+                for op in ops {
+                    self.apply_operation(op)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
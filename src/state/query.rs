@@ -0,0 +1,407 @@
+//! Textual tag queries.
+//!
+//! ```text
+//! photos/2024 AND (family OR friends) AND NOT private
+//! photos/2024 & (family | friends) & !private
+//! "my stuff"/"2024 summer" OR #12
+//! ```
+//!
+//! `NOT` binds tighter than `AND`, which binds tighter than `OR`. Keywords are
+//! case-insensitive; quote a segment to use a keyword or one of `()&|!"` in a
+//! tag name. A `/` always separates segments, quoted or not, so `"a/b"` is the
+//! tag `b` under `a`. `#<id>` refers to a live tag by id. Parentheses and
+//! `NOT`s may nest at most [`MAX_QUERY_DEPTH`] levels deep; chains of `AND`
+//! or `OR` may be any length.
+
+use std::fmt;
+
+use crate::{ state::repository::{ Repository, TagQuery }, tag::TagId };
+
+/// Deepest nesting of parentheses and `NOT`s [`Repository::parse_query`]
+/// accepts. `AND` and `OR` chains are parsed into balanced trees, so together
+/// this bounds how deep evaluating and dropping a query recurses.
+pub const MAX_QUERY_DEPTH: usize = 256;
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("{kind} at position {position}")]
+pub struct QueryParseError {
+    /// Byte offset into the query text.
+    pub position: usize,
+    pub kind: QueryParseErrorKind,
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum QueryParseErrorKind {
+    #[error("unexpected end of query")]
+    UnexpectedEnd,
+    #[error("unexpected `{0}`")] UnexpectedToken(String),
+    #[error("unterminated quote")]
+    UnterminatedQuote,
+    #[error("empty path segment")]
+    EmptySegment,
+    #[error("unknown tag `{0}`")] UnknownTag(String),
+    #[error("query nested too deeply")]
+    TooDeep,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+    Path(Vec<String>),
+    Id(TagId),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::And => f.write_str("AND"),
+            Token::Or => f.write_str("OR"),
+            Token::Not => f.write_str("NOT"),
+            Token::Open => f.write_str("("),
+            Token::Close => f.write_str(")"),
+            Token::Path(path) => f.write_str(&format_path(path)),
+            Token::Id(id) => write!(f, "#{}", id.0),
+        }
+    }
+}
+
+const SPECIAL: &[char] = &['(', ')', '&', '|', '!', '"', '/'];
+
+fn is_bare_char(c: char) -> bool {
+    !c.is_whitespace() && !SPECIAL.contains(&c)
+}
+
+fn error(position: usize, kind: QueryParseErrorKind) -> QueryParseError {
+    QueryParseError { position, kind }
+}
+
+fn tokenize(input: &str) -> Result<Vec<(usize, Token)>, QueryParseError> {
+    let mut tokens = Vec::new();
+    let mut chars = input.char_indices().peekable();
+
+    while let Some(&(start, c)) = chars.peek() {
+        let token = match c {
+            c if c.is_whitespace() => {
+                chars.next();
+                continue;
+            }
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '&' => Token::And,
+            '|' => Token::Or,
+            '!' => Token::Not,
+            _ => {
+                tokens.push((start, read_path(input, &mut chars)?));
+                continue;
+            }
+        };
+        chars.next();
+        tokens.push((start, token));
+    }
+
+    Ok(tokens)
+}
+
+/// Reads `segment ("/" segment)*`, or a keyword / `#id` if the token is a
+/// single unquoted segment.
+fn read_path(
+    input: &str,
+    chars: &mut std::iter::Peekable<std::str::CharIndices<'_>>
+) -> Result<Token, QueryParseError> {
+    let start = chars.peek().map_or(input.len(), |(i, _)| *i);
+    let mut segments: Vec<String> = Vec::new();
+    let mut quoted_any = false;
+
+    loop {
+        let segment_start = chars.peek().map_or(input.len(), |(i, _)| *i);
+        let mut segment = String::new();
+
+        if chars.peek().is_some_and(|(_, c)| *c == '"') {
+            quoted_any = true;
+            chars.next();
+            loop {
+                match chars.next() {
+                    None => {
+                        return Err(error(segment_start, QueryParseErrorKind::UnterminatedQuote));
+                    }
+                    Some((_, '"')) => {
+                        break;
+                    }
+                    Some((_, '\\')) =>
+                        match chars.next() {
+                            Some((_, escaped)) => segment.push(escaped),
+                            None => {
+                                return Err(
+                                    error(segment_start, QueryParseErrorKind::UnterminatedQuote)
+                                );
+                            }
+                        }
+                    Some((_, c)) => segment.push(c),
+                }
+            }
+        } else {
+            while let Some(&(_, c)) = chars.peek() {
+                if !is_bare_char(c) {
+                    break;
+                }
+                segment.push(c);
+                chars.next();
+            }
+        }
+
+        if segment.is_empty() {
+            return Err(error(segment_start, QueryParseErrorKind::EmptySegment));
+        }
+        segments.push(segment);
+
+        if chars.peek().is_some_and(|(_, c)| *c == '/') {
+            chars.next();
+        } else {
+            break;
+        }
+    }
+
+    if segments.len() == 1 && !quoted_any {
+        let word = segments[0].as_str();
+        match word.to_ascii_uppercase().as_str() {
+            "AND" => {
+                return Ok(Token::And);
+            }
+            "OR" => {
+                return Ok(Token::Or);
+            }
+            "NOT" => {
+                return Ok(Token::Not);
+            }
+            _ => {}
+        }
+        if let Some(id) = word.strip_prefix('#') {
+            return id
+                .parse()
+                .map(|id| Token::Id(TagId(id)))
+                .map_err(|_| error(start, QueryParseErrorKind::UnknownTag(word.to_string())));
+        }
+    }
+
+    Ok(Token::Path(segments))
+}
+
+struct Parser<'a> {
+    repo: &'a Repository,
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+    end: usize,
+
+    /// Parentheses and `NOT`s currently open.
+    nesting: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, t)| t)
+    }
+
+    fn position(&self) -> usize {
+        self.tokens.get(self.pos).map_or(self.end, |(p, _)| *p)
+    }
+
+    fn parse_or(&mut self) -> Result<TagQuery, QueryParseError> {
+        let mut operands = vec![self.parse_and()?];
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            operands.push(self.parse_and()?);
+        }
+        Ok(balanced(operands, TagQuery::Or))
+    }
+
+    fn parse_and(&mut self) -> Result<TagQuery, QueryParseError> {
+        let mut operands = vec![self.parse_unary()?];
+        while self.peek() == Some(&Token::And) {
+            self.pos += 1;
+            operands.push(self.parse_unary()?);
+        }
+        Ok(balanced(operands, TagQuery::And))
+    }
+
+    fn parse_unary(&mut self) -> Result<TagQuery, QueryParseError> {
+        let position = self.position();
+        let Some((_, token)) = self.tokens.get(self.pos).cloned() else {
+            return Err(error(position, QueryParseErrorKind::UnexpectedEnd));
+        };
+        self.pos += 1;
+
+        if matches!(token, Token::Not | Token::Open) {
+            if self.nesting >= MAX_QUERY_DEPTH {
+                return Err(error(position, QueryParseErrorKind::TooDeep));
+            }
+            self.nesting += 1;
+            let parsed = self.parse_nested(token);
+            self.nesting -= 1;
+            return parsed;
+        }
+
+        match token {
+            Token::Path(path) => {
+                let path_str = path.join("/");
+                match self.repo.tag_paths.by_path.get(&path_str) {
+                    Some(tag) => Ok(TagQuery::Tag(*tag)),
+                    None => Err(error(position, QueryParseErrorKind::UnknownTag(path_str))),
+                }
+            }
+            Token::Id(tag) => {
+                // trashed tags are left out of searches, so they would only
+                // fail once the query is evaluated
+                if !self.repo.is_live_tag(tag) {
+                    let kind = QueryParseErrorKind::UnknownTag(format!("#{}", tag.0));
+                    return Err(error(position, kind));
+                }
+                Ok(TagQuery::Tag(tag))
+            }
+            other => Err(error(position, QueryParseErrorKind::UnexpectedToken(other.to_string()))),
+        }
+    }
+
+    /// The operand of a `NOT` or the contents of parentheses.
+    fn parse_nested(&mut self, token: Token) -> Result<TagQuery, QueryParseError> {
+        match token {
+            Token::Not => Ok(TagQuery::Not(Box::new(self.parse_unary()?))),
+            _ => {
+                let parsed = self.parse_or()?;
+                match self.peek() {
+                    Some(Token::Close) => {
+                        self.pos += 1;
+                        Ok(parsed)
+                    }
+                    Some(other) => {
+                        let kind = QueryParseErrorKind::UnexpectedToken(other.to_string());
+                        Err(error(self.position(), kind))
+                    }
+                    None => Err(error(self.end, QueryParseErrorKind::UnexpectedEnd)),
+                }
+            }
+        }
+    }
+}
+
+/// Joins the operands of an `AND` or `OR` chain into a tree of logarithmic
+/// depth. Both operators are associative, so the shape does not change what
+/// the query matches.
+fn balanced(
+    mut operands: Vec<TagQuery>,
+    join: fn(Box<TagQuery>, Box<TagQuery>) -> TagQuery
+) -> TagQuery {
+    if operands.len() == 1 {
+        return operands.pop().unwrap();
+    }
+    let right = operands.split_off(operands.len().div_ceil(2));
+    join(Box::new(balanced(operands, join)), Box::new(balanced(right, join)))
+}
+
+fn format_segment(segment: &str) -> String {
+    let is_keyword = matches!(segment.to_ascii_uppercase().as_str(), "AND" | "OR" | "NOT");
+    let needs_quotes =
+        segment.is_empty() ||
+        is_keyword ||
+        segment.starts_with('#') ||
+        !segment.chars().all(is_bare_char);
+
+    if !needs_quotes {
+        return segment.to_string();
+    }
+
+    let mut quoted = String::from("\"");
+    for c in segment.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn format_path(path: &[String]) -> String {
+    path.iter()
+        .map(|segment| format_segment(segment))
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+impl Repository {
+    /// Parses a textual query, resolving tag paths through the path index.
+    pub fn parse_query(&self, input: &str) -> Result<TagQuery, QueryParseError> {
+        let mut parser = Parser {
+            repo: self,
+            tokens: tokenize(input)?,
+            pos: 0,
+            end: input.len(),
+            nesting: 0,
+        };
+
+        let query = parser.parse_or()?;
+        if let Some(token) = parser.peek() {
+            let kind = QueryParseErrorKind::UnexpectedToken(token.to_string());
+            return Err(error(parser.position(), kind));
+        }
+
+        Ok(query)
+    }
+
+    /// Formats a query as text accepted by [`parse_query`](Self::parse_query).
+    ///
+    /// Tags are written by path, or as `#<id>` where the path would not lead
+    /// back to them: tags sharing their path with a lower id. Trashed tags
+    /// and tags without a record are written as `#<id>` as well, which does
+    /// not parse.
+    pub fn format_query(&self, query: &TagQuery) -> String {
+        let mut out = String::new();
+        self.write_query(&mut out, query, 0);
+        out
+    }
+
+    fn write_query(&self, out: &mut String, query: &TagQuery, min_precedence: u8) {
+        let precedence = match query {
+            TagQuery::Or(..) => 1,
+            TagQuery::And(..) => 2,
+            TagQuery::Not(_) | TagQuery::Tag(_) => 3,
+        };
+        let parenthesize = precedence < min_precedence;
+        if parenthesize {
+            out.push('(');
+        }
+
+        match query {
+            TagQuery::Tag(tag) => {
+                let path = self.tags
+                    .get(tag)
+                    .filter(|record| !record.deleted)
+                    .map(|record| record.get_path())
+                    .filter(|path| self.tag_paths.by_path.get(&path.join("/")) == Some(tag));
+                match path {
+                    Some(path) => out.push_str(&format_path(path)),
+                    None => out.push_str(&format!("#{}", tag.0)),
+                }
+            }
+            TagQuery::Or(a, b) | TagQuery::And(a, b) => {
+                let keyword = if precedence == 1 { " OR " } else { " AND " };
+                // both operators are associative, so neither operand of
+                // equal precedence needs parentheses
+                self.write_query(out, a, precedence);
+                out.push_str(keyword);
+                self.write_query(out, b, precedence);
+            }
+            TagQuery::Not(q) => {
+                out.push_str("NOT ");
+                self.write_query(out, q, 3);
+            }
+        }
+
+        if parenthesize {
+            out.push(')');
+        }
+    }
+}
@@ -1,18 +1,52 @@
-use std::collections::HashMap;
+use std::{ collections::{ BTreeMap, BTreeSet, HashMap, HashSet }, ops::{ Bound, RangeBounds } };
 
+use chrono::{ DateTime, Utc };
+use rayon::prelude::*;
 use roaring::RoaringBitmap;
 use serde::{ Deserialize, Serialize, ser::SerializeStruct };
 
 use crate::{
-    blob::{ BlobError, DataBlob },
-    node::{ NodeId, NodeRecord },
-    tag::{ TagHierarchyIndex, TagId, TagMembershipIndex, TagPathIndex, TagRecord },
+    blob::{ BlobError, BlobId, DataBlob },
+    hlc::Hlc,
+    node::{ NodeId, NodeRecord, NodeTimeIndex },
+    state::{
+        events::Subscribers,
+        history::{ Change, History },
+        integrity::{ IntegrityIssue, IntegrityMode },
+        oplog::{ OpLog, Operation },
+    },
+    tag::{
+        TagColors,
+        TagHierarchyIndex,
+        TagId,
+        TagMembershipIndex,
+        TagPathIndex,
+        TagRecord,
+    },
+    uid::{ Uid, UidIndex, UidKind },
 };
 
 #[derive(Deserialize)]
 struct RepositorySerde {
     nodes: HashMap<NodeId, NodeRecord>,
     tags: HashMap<TagId, TagRecord>,
+    #[serde(default)]
+    last_stamp: Hlc,
+    #[serde(default)]
+    next_node_id: NodeId,
+    #[serde(default)]
+    next_tag_id: TagId,
+    #[serde(default)]
+    oplog: OpLog,
+}
+
+/// What [`Repository::save_to_json`] writes: the records and the log of the
+/// operations that produced them.
+#[derive(Serialize)]
+struct SavedRepository<'a> {
+    #[serde(flatten)]
+    repository: &'a Repository,
+    oplog: &'a OpLog,
 }
 
 #[derive(Clone, Debug, Default)]
@@ -24,9 +58,32 @@ pub struct Repository {
     pub tag_paths: TagPathIndex,
     pub tag_hierarchy: TagHierarchyIndex,
     pub tag_membership: TagMembershipIndex,
+    pub node_times: NodeTimeIndex,
+    pub uids: UidIndex,
 
     pub next_node_id: NodeId,
     pub next_tag_id: TagId,
+
+    pub(crate) oplog: OpLog,
+    pub(crate) history: History,
+    pub(crate) subscribers: Subscribers,
+
+    /// Time used instead of the system clock while an operation is applied.
+    pub(crate) clock: Option<DateTime<Utc>>,
+
+    /// The latest merge stamp issued or observed, see [`Hlc`].
+    pub(crate) last_stamp: Hlc,
+
+    /// Merge stamp shared by every record the running operation writes.
+    pub(crate) operation_stamp: Option<Hlc>,
+
+    /// Set while a large batch is written; the derived indexes other than
+    /// the uid index are rebuilt once the batch is stored.
+    pub(crate) indexes_deferred: bool,
+
+    /// Nodes loaded with a date that could not be read, reported by
+    /// [`validate`](Self::validate) until [`repair`](Self::repair).
+    pub(crate) unrecognized_timestamps: BTreeSet<NodeId>,
 }
 
 impl Repository {
@@ -38,141 +95,685 @@ impl Repository {
         Self::default()
     }
 
+    /// Loads a repository saved by [`save_to_json`](Self::save_to_json),
+    /// without checking its records; see
+    /// [`load_from_json_with`](Self::load_from_json_with).
     pub fn load_from_json(json: &str) -> Result<Self, RepoError> {
+        Self::load_from_json_with(json, IntegrityMode::Accept)
+    }
+
+    /// Loads a repository, handling records that fail
+    /// [`validate`](Self::validate) as `integrity` says.
+    pub fn load_from_json_with(json: &str, integrity: IntegrityMode) -> Result<Self, RepoError> {
         let mut repo: Repository = serde_json
             ::from_str(json)
             .map_err(|_| RepoError::Serialization)?;
+        repo.finish_load();
+
+        match integrity {
+            IntegrityMode::Accept => {}
+            IntegrityMode::Reject => {
+                let issues = repo.validate();
+                if !issues.is_empty() {
+                    return Err(RepoError::Integrity(issues));
+                }
+            }
+            IntegrityMode::Repair => {
+                repo.repair();
+            }
+        }
+
+        Ok(repo)
+    }
+
+    /// Derives the id counters and indexes of freshly deserialized records,
+    /// giving records from older archives their legacy [`Uid`].
+    ///
+    /// Saved counters are kept when ahead of the records, so ids of erased
+    /// records are not handed out again and replays allocate the same ids.
+    pub(crate) fn finish_load(&mut self) {
+        for (id, node) in self.nodes.iter_mut() {
+            if node.replace_unrecognized_dates() {
+                self.unrecognized_timestamps.insert(*id);
+            }
+        }
+        for (id, node) in self.nodes.iter_mut().filter(|(_, node)| node.uid.is_nil()) {
+            node.uid = Uid::legacy_node(*id, node.date_created);
+        }
+        for (id, tag) in self.tags.iter_mut().filter(|(_, tag)| tag.uid.is_nil()) {
+            tag.uid = Uid::legacy_tag(*id, &tag.path);
+        }
 
-        repo.next_tag_id = (
-            repo.tags
+        self.next_tag_id = self.next_tag_id.max(
+            self.tags
                 .keys()
-                .map(|id| id.0)
+                .map(|id| id.0 + 1)
                 .max()
-                .unwrap_or(0) + 1
-        ).into();
-        repo.next_node_id = (
-            repo.nodes
+                .unwrap_or(0)
+                .into()
+        );
+        self.next_node_id = self.next_node_id.max(
+            self.nodes
                 .keys()
-                .map(|id| id.0)
+                .map(|id| id.0 + 1)
                 .max()
-                .unwrap_or(0) + 1
-        ).into();
-
-        repo.rebuild_all_indexes();
+                .unwrap_or(0)
+                .into()
+        );
+        self.last_stamp = self.last_stamp.max(self.latest_stamp());
 
-        Ok(repo)
+        self.rebuild_all_indexes();
     }
 
+    /// Serializes the records together with the operation log, so a loaded
+    /// repository keeps its history for replay and sync.
     pub fn save_to_json(&self) -> Result<String, RepoError> {
-        let json = serde_json::to_string_pretty(self).map_err(|_| RepoError::Serialization)?;
+        let saved = SavedRepository { repository: self, oplog: &self.oplog };
+        let json = serde_json::to_string_pretty(&saved).map_err(|_| RepoError::Serialization)?;
         Ok(json)
     }
 
+    /// Serializes the records alone, e.g. for a snapshot that refers to the
+    /// log by position.
+    pub(crate) fn records_to_json(&self) -> Result<String, RepoError> {
+        serde_json::to_string_pretty(self).map_err(|_| RepoError::Serialization)
+    }
+
     // ----------------------------
     // Index rebuild / maintenance
     // ----------------------------
 
+    /// Rebuilds every derived index from the node and tag records.
+    ///
+    /// Only needed after loading or repairing; mutations keep the indexes up
+    /// to date incrementally. Target: 1M nodes with three tags each and 100k
+    /// tags rebuild in under one second on an 8-core machine in a release
+    /// build (see `examples/index_bench.rs`).
     pub fn rebuild_all_indexes(&mut self) {
         self.rebuild_tag_path_index();
         self.rebuild_tag_hierarchy_from_paths();
         self.rebuild_tag_membership_indexes();
+        self.rebuild_node_time_index();
+        self.rebuild_uid_index();
+    }
+
+    /// Records without a uid are indexed under their legacy uid; duplicate
+    /// uids resolve to the lowest id.
+    pub fn rebuild_uid_index(&mut self) {
+        let nodes: Vec<(Uid, NodeId)> = self.nodes
+            .par_iter()
+            .map(|(id, node)| (Uid::of_node(*id, node), *id))
+            .collect();
+        let tags: Vec<(Uid, TagId)> = self.tags
+            .par_iter()
+            .map(|(id, tag)| (Uid::of_tag(*id, tag), *id))
+            .collect();
+
+        self.uids = UidIndex {
+            nodes: lowest_id_per_uid(nodes),
+            tags: lowest_id_per_uid(tags),
+        };
+    }
+
+    pub fn rebuild_node_time_index(&mut self) {
+        // sorted input lets the sets bulk-build instead of inserting one by one
+        let sorted = |date: fn(&NodeRecord) -> DateTime<Utc>| {
+            let mut entries: Vec<(DateTime<Utc>, NodeId)> = self.nodes
+                .par_iter()
+                .filter(|(_, node)| !node.deleted)
+                .map(|(_, node)| (date(node), *node.get_id()))
+                .collect();
+            entries.par_sort_unstable();
+            entries.into_iter().collect()
+        };
+
+        self.node_times = NodeTimeIndex {
+            created: sorted(|node| node.date_created),
+            updated: sorted(|node| node.date_updated),
+        };
     }
 
     pub fn rebuild_tag_path_index(&mut self) {
-        let mut path_map: HashMap<String, TagId> = HashMap::new();
+        let mut path_map: HashMap<String, BTreeSet<TagId>> = HashMap::new();
 
         for tag in self.iter_tags() {
-            let path_str = tag.get_path().join("/");
-            path_map.insert(path_str, *tag.get_id());
+            path_map.entry(tag.get_path().join("/")).or_default().insert(*tag.get_id());
+        }
+
+        // duplicate paths resolve to the lowest id
+        let mut by_path = HashMap::with_capacity(path_map.len());
+        let mut shadowed = HashMap::new();
+        for (path_str, mut ids) in path_map {
+            let owner = ids.pop_first().expect("path has at least one tag");
+            if !ids.is_empty() {
+                shadowed.insert(path_str.clone(), ids);
+            }
+            by_path.insert(path_str, owner);
         }
 
-        self.tag_paths.by_path = path_map.clone();
+        self.tag_paths.by_path = by_path;
+        self.tag_paths.shadowed = shadowed;
     }
 
+    /// Requires an up-to-date [`TagPathIndex`].
     pub fn rebuild_tag_hierarchy_from_paths(&mut self) {
-        let mut parent_map: HashMap<TagId, Option<TagId>> = HashMap::new();
+        let mut parent_map: HashMap<TagId, Option<TagId>> = HashMap::with_capacity(self.tags.len());
         let mut children_map: HashMap<TagId, Vec<TagId>> = HashMap::new();
+        let mut orphans: HashMap<String, BTreeSet<TagId>> = HashMap::new();
 
         for tag in self.iter_tags() {
             let path = tag.get_path();
             let tag_id = *tag.get_id();
 
-            let parent_id = if path.len() == 1 {
-                None
-            } else {
-                let parent_path = &path[..path.len() - 1];
-
-                // optimization: HashMap for path -> TagId?
-                let parent_tag = self.tags.values().find(|t| t.get_path() == parent_path);
-                parent_tag.map(|t| *t.get_id())
-            };
+            let parent_id = self.parent_by_path(path);
             parent_map.insert(tag_id, parent_id);
 
             if let Some(parent_id) = parent_id {
                 children_map.entry(parent_id).or_default().push(tag_id);
+            } else if path.len() > 1 {
+                orphans.entry(path[..path.len() - 1].join("/")).or_default().insert(tag_id);
             }
         }
 
         self.tag_hierarchy.parent = parent_map;
         self.tag_hierarchy.children = children_map;
+        self.tag_hierarchy.orphans = orphans;
     }
 
+    /// Requires an up-to-date [`TagHierarchyIndex`].
+    ///
+    /// Direct bitmaps are built from a parallel sort of all (tag, node) pairs.
+    /// Subtree bitmaps are then folded bottom-up from the children, one depth
+    /// level at a time with the tags of a level in parallel.
     pub fn rebuild_tag_membership_indexes(&mut self) {
-        let mut direct_nodes: HashMap<TagId, RoaringBitmap> = HashMap::new();
-        let mut subtree_nodes: HashMap<TagId, RoaringBitmap> = HashMap::new();
+        // (tag, node) pairs packed into u64 sort by tag, then by node
+        let mut pairs: Vec<u64> = self.nodes
+            .par_iter()
+            .filter(|(_, node)| !node.deleted)
+            .flat_map_iter(|(id, node)| {
+                node.get_tags()
+                    .iter()
+                    .map(move |tag| ((tag.0 as u64) << 32) | (id.0 as u64))
+            })
+            .collect();
+        pairs.par_sort_unstable();
+        pairs.dedup();
+
+        let (direct_nodes, dormant_nodes): (HashMap<TagId, RoaringBitmap>, HashMap<_, _>) = pairs
+            .par_chunk_by(|a, b| a >> 32 == b >> 32)
+            .map(|group| {
+                let tag = TagId((group[0] >> 32) as u32);
+                let bitmap = RoaringBitmap::from_sorted_iter(group.iter().map(|pair| *pair as u32));
+                (tag, bitmap.expect("pairs are sorted and deduplicated"))
+            })
+            .partition(|(tag, _)| self.is_live_tag(*tag));
+
+        let mut levels: Vec<Vec<TagId>> = Vec::new();
+        for tag in self.iter_tags() {
+            let depth = tag.get_path().len();
+            if levels.len() < depth {
+                levels.resize_with(depth, Vec::new);
+            }
+            if depth > 0 {
+                levels[depth - 1].push(*tag.get_id());
+            }
+        }
 
-        for node in self.iter_nodes() {
-            for tag_id in node.get_tags() {
-                direct_nodes.entry(*tag_id).or_default().insert(node.get_id().0);
-
-                // propagate to ancestors
-                let mut current_tag_id = *tag_id;
-                while let Some(parent_id_opt) = self.tag_hierarchy.parent.get(&current_tag_id) {
-                    if let Some(parent_id) = parent_id_opt {
-                        subtree_nodes.entry(*parent_id).or_default().insert(node.get_id().0);
-                        current_tag_id = *parent_id;
-                    } else {
-                        break;
+        // children always sit one level deeper than their parent
+        let mut subtree_nodes: HashMap<TagId, RoaringBitmap> = HashMap::new();
+        for level in levels.iter().rev() {
+            let computed: Vec<(TagId, RoaringBitmap)> = level
+                .par_iter()
+                .filter_map(|tag| {
+                    let children = self.tag_hierarchy.children.get(tag)?;
+                    let mut bitmap = RoaringBitmap::new();
+                    for child in children {
+                        if let Some(direct) = direct_nodes.get(child) {
+                            bitmap |= direct;
+                        }
+                        if let Some(subtree) = subtree_nodes.get(child) {
+                            bitmap |= subtree;
+                        }
                     }
-                }
-            }
+                    (!bitmap.is_empty()).then_some((*tag, bitmap))
+                })
+                .collect();
+            subtree_nodes.extend(computed);
         }
 
         self.tag_membership.direct_nodes = direct_nodes;
         self.tag_membership.subtree_nodes = subtree_nodes;
+        self.tag_membership.dormant_nodes = dormant_nodes;
+    }
+
+    /// Compares the incrementally maintained indexes against a full rebuild,
+    /// describing the first mismatch. Meant for debugging and tests; this is
+    /// as expensive as [`rebuild_all_indexes`](Self::rebuild_all_indexes).
+    pub fn check_index_consistency(&self) -> Result<(), RepoError> {
+        let mut rebuilt = self.clone();
+        rebuilt.rebuild_all_indexes();
+
+        let sorted_children = |index: &TagHierarchyIndex| {
+            let mut children = index.children.clone();
+            for ids in children.values_mut() {
+                ids.sort_by_key(|id| id.0);
+            }
+            children
+        };
+
+        let mismatch = if self.tag_paths.by_path != rebuilt.tag_paths.by_path {
+            Some("tag path index")
+        } else if self.tag_paths.shadowed != rebuilt.tag_paths.shadowed {
+            Some("shadowed tag paths")
+        } else if self.tag_hierarchy.parent != rebuilt.tag_hierarchy.parent {
+            Some("tag parents")
+        } else if sorted_children(&self.tag_hierarchy) != sorted_children(&rebuilt.tag_hierarchy) {
+            Some("tag children")
+        } else if self.tag_hierarchy.orphans != rebuilt.tag_hierarchy.orphans {
+            Some("orphan tags")
+        } else if self.tag_membership.direct_nodes != rebuilt.tag_membership.direct_nodes {
+            Some("direct tag membership")
+        } else if self.tag_membership.subtree_nodes != rebuilt.tag_membership.subtree_nodes {
+            Some("subtree tag membership")
+        } else if self.tag_membership.dormant_nodes != rebuilt.tag_membership.dormant_nodes {
+            Some("dormant tag membership")
+        } else if self.node_times != rebuilt.node_times {
+            Some("node time index")
+        } else if self.uids != rebuilt.uids {
+            Some("uid index")
+        } else {
+            None
+        };
+
+        match mismatch {
+            Some(index) => Err(RepoError::Other(format!("inconsistent index: {}", index))),
+            None => Ok(()),
+        }
     }
 
     // ----------------------------
-    // Node operations
+    // Incremental index maintenance
     // ----------------------------
 
-    pub fn upsert_node(&mut self, node: NodeRecord) -> Result<NodeId, RepoError> {
+    /// Time stamped on records by mutations.
+    pub(crate) fn now(&self) -> DateTime<Utc> {
+        self.clock.unwrap_or_else(Utc::now)
+    }
+
+    /// Stores a node record, updating the indexes for the old and new record.
+    pub(crate) fn write_node(&mut self, mut node: NodeRecord) -> Option<NodeRecord> {
         let node_id = *node.get_id();
-        self.nodes.insert(node_id, node);
+        let now = self.now();
+        node.deleted_at = deletion_stamp(node.deleted, node.deleted_at, now);
+        node.date_updated = now;
+
+        match self.nodes.get(&node_id) {
+            Some(old) => {
+                node.date_created = old.date_created;
+                node.uid = old.uid;
+            }
+            None if node.uid.is_nil() || self.uids.nodes.contains_key(&node.uid) => {
+                node.uid = Uid::derived(self.stamp(), UidKind::Node, node_id.0);
+            }
+            None => {}
+        }
+        self.stamp_node(&mut node);
 
-        if node_id.0 == self.next_node_id.0 {
-            self.next_node_id.0 = node_id.0 + 1;
+        self.put_node(node_id, Some(node))
+    }
+
+    /// Stores a tag record, updating the indexes for the old and new record.
+    pub(crate) fn write_tag(&mut self, mut tag: TagRecord) -> Option<TagRecord> {
+        let tag_id = *tag.get_id();
+        tag.deleted_at = deletion_stamp(tag.deleted, tag.deleted_at, self.now());
+
+        match self.tags.get(&tag_id) {
+            Some(old) => {
+                tag.uid = old.uid;
+            }
+            None if tag.uid.is_nil() || self.uids.tags.contains_key(&tag.uid) => {
+                tag.uid = Uid::derived(self.stamp(), UidKind::Tag, tag_id.0);
+            }
+            None => {}
         }
+        self.stamp_tag(&mut tag);
 
-        self.rebuild_all_indexes();
+        self.put_tag(tag_id, Some(tag))
+    }
 
-        Ok(node_id)
+    /// Physically removes a node record.
+    pub(crate) fn erase_node(&mut self, node: NodeId) -> Option<NodeRecord> {
+        self.put_node(node, None)
     }
 
-    pub fn delete_node(&mut self, node: NodeId) -> Result<(), RepoError> {
-        let node = self.nodes
-            .get_mut(&node)
-            .ok_or_else(|| RepoError::NotFound("delete_node: node not found".to_string()))?;
-        node.deleted = true;
+    /// Physically removes a tag record, first dropping it from every node
+    /// that references it.
+    pub(crate) fn erase_tag(&mut self, tag: TagId) -> Option<TagRecord> {
+        let referencing: Vec<NodeRecord> = self.nodes
+            .values()
+            .filter(|node| node.tags.contains(&tag))
+            .cloned()
+            .collect();
+        for mut node in referencing {
+            node.tags.retain(|t| *t != tag);
+            self.write_node(node);
+        }
 
-        self.rebuild_all_indexes();
-        Ok(())
+        self.put_tag(tag, None)
+    }
+
+    /// Replaces or removes a node record exactly as given, keeping the
+    /// indexes up to date and recording the change for undo.
+    pub(crate) fn put_node(
+        &mut self,
+        node_id: NodeId,
+        node: Option<NodeRecord>
+    ) -> Option<NodeRecord> {
+        let old = self.nodes.remove(&node_id);
+        if let Some(old) = &old {
+            if !self.indexes_deferred {
+                self.unindex_node(old);
+            }
+            let uid = Uid::of_node(node_id, old);
+            if self.uids.nodes.get(&uid) == Some(&node_id) {
+                self.uids.nodes.remove(&uid);
+            }
+        }
+        if let Some(node) = node {
+            if !self.indexes_deferred {
+                self.index_node(&node);
+            }
+            self.uids.nodes.insert(Uid::of_node(node_id, &node), node_id);
+            self.nodes.insert(node_id, node);
+        }
+
+        self.history.record(Change::Node(node_id, old.clone()));
+        old
+    }
+
+    /// Replaces or removes a tag record exactly as given, keeping the indexes
+    /// up to date and recording the change for undo.
+    pub(crate) fn put_tag(
+        &mut self,
+        tag_id: TagId,
+        tag: Option<TagRecord>
+    ) -> Option<TagRecord> {
+        // the old record stays in place while it is unindexed
+        if let Some(old) = self.tags.get(&tag_id).cloned() {
+            if !self.indexes_deferred {
+                self.unindex_tag(&old);
+            }
+            let uid = Uid::of_tag(tag_id, &old);
+            if self.uids.tags.get(&uid) == Some(&tag_id) {
+                self.uids.tags.remove(&uid);
+            }
+        }
+        let old = match tag {
+            Some(tag) => {
+                let old = self.tags.insert(tag_id, tag.clone());
+                if !self.indexes_deferred {
+                    self.index_tag(&tag);
+                }
+                self.uids.tags.insert(Uid::of_tag(tag_id, &tag), tag_id);
+                old
+            }
+            None => self.tags.remove(&tag_id),
+        };
+
+        self.history.record(Change::Tag(tag_id, old.clone()));
+        old
+    }
+
+    fn index_node(&mut self, node: &NodeRecord) {
+        if node.deleted {
+            return;
+        }
+        let node_ix = node.get_id().0;
+
+        self.node_times.created.insert((*node.get_date_created(), *node.get_id()));
+        self.node_times.updated.insert((*node.get_date_updated(), *node.get_id()));
+
+        for tag in node.get_tags() {
+            if !self.is_live_tag(*tag) {
+                self.tag_membership.dormant_nodes.entry(*tag).or_default().insert(node_ix);
+                continue;
+            }
+
+            self.tag_membership.direct_nodes.entry(*tag).or_default().insert(node_ix);
+            for ancestor in self.tag_ancestors(*tag) {
+                self.tag_membership.subtree_nodes.entry(ancestor).or_default().insert(node_ix);
+            }
+        }
+    }
+
+    fn unindex_node(&mut self, node: &NodeRecord) {
+        if node.deleted {
+            return;
+        }
+        let node_ix = node.get_id().0;
+
+        self.node_times.created.remove(&(*node.get_date_created(), *node.get_id()));
+        self.node_times.updated.remove(&(*node.get_date_updated(), *node.get_id()));
+
+        // all tags of the node go at once, so no other tag keeps a bit alive
+        for tag in node.get_tags() {
+            remove_bit(&mut self.tag_membership.dormant_nodes, *tag, node_ix);
+            remove_bit(&mut self.tag_membership.direct_nodes, *tag, node_ix);
+            for ancestor in self.tag_ancestors(*tag) {
+                remove_bit(&mut self.tag_membership.subtree_nodes, ancestor, node_ix);
+            }
+        }
+    }
+
+    fn index_tag(&mut self, tag: &TagRecord) {
+        if tag.deleted {
+            return;
+        }
+        let tag_id = *tag.get_id();
+        let path = tag.get_path();
+
+        if let Some(bitmap) = self.tag_membership.dormant_nodes.remove(&tag_id) {
+            self.tag_membership.direct_nodes.insert(tag_id, bitmap);
+        }
+
+        let parent = self.parent_by_path(path);
+        let parent_path = parent_path_of(path);
+        self.set_tag_parent(tag_id, parent, parent_path.as_deref());
+
+        let path_str = path.join("/");
+        match self.tag_paths.by_path.get(&path_str).copied() {
+            None => {
+                // tags below this path no longer lack a parent
+                let adopted = self.tag_hierarchy.orphans.remove(&path_str).unwrap_or_default();
+                for child in adopted {
+                    self.set_tag_parent(child, Some(tag_id), None);
+                }
+
+                self.tag_paths.by_path.insert(path_str, tag_id);
+                self.refresh_subtree(tag_id);
+            }
+            Some(owner) if tag_id.0 < owner.0 => {
+                self.tag_paths.shadowed.entry(path_str.clone()).or_default().insert(owner);
+                self.tag_paths.by_path.insert(path_str, tag_id);
+                self.transfer_children(owner, tag_id);
+            }
+            // a duplicate path that does not own it has no children
+            Some(_) => {
+                self.tag_paths.shadowed.entry(path_str).or_default().insert(tag_id);
+            }
+        }
+
+        self.refresh_subtree_upwards(parent);
+    }
+
+    fn unindex_tag(&mut self, tag: &TagRecord) {
+        if tag.deleted {
+            return;
+        }
+        let tag_id = *tag.get_id();
+        let path = tag.get_path();
+
+        let parent = self.tag_hierarchy.parent.remove(&tag_id).flatten();
+        match (parent, parent_path_of(path)) {
+            (Some(parent), _) => remove_child(&mut self.tag_hierarchy.children, parent, tag_id),
+            (None, Some(parent_path)) => {
+                remove_from_group(&mut self.tag_hierarchy.orphans, &parent_path, tag_id);
+            }
+            (None, None) => {}
+        }
+
+        let path_str = path.join("/");
+        if self.tag_paths.by_path.get(&path_str) == Some(&tag_id) {
+            self.tag_paths.by_path.remove(&path_str);
+
+            // another tag with the same path takes over, if there is one
+            let successor = match self.tag_paths.shadowed.get_mut(&path_str) {
+                Some(ids) => {
+                    let successor = ids.pop_first();
+                    if ids.is_empty() {
+                        self.tag_paths.shadowed.remove(&path_str);
+                    }
+                    successor
+                }
+                None => None,
+            };
+
+            match successor {
+                Some(successor) => {
+                    self.tag_paths.by_path.insert(path_str, successor);
+                    self.transfer_children(tag_id, successor);
+                }
+                None => {
+                    let children = self.tag_hierarchy.children.remove(&tag_id).unwrap_or_default();
+                    for child in children {
+                        self.set_tag_parent(child, None, Some(&path_str));
+                    }
+                }
+            }
+        } else {
+            remove_from_group(&mut self.tag_paths.shadowed, &path_str, tag_id);
+        }
+        self.tag_membership.subtree_nodes.remove(&tag_id);
+        if let Some(bitmap) = self.tag_membership.direct_nodes.remove(&tag_id) {
+            self.tag_membership.dormant_nodes.insert(tag_id, bitmap);
+        }
+
+        self.refresh_subtree_upwards(parent);
+    }
+
+    fn parent_by_path(&self, path: &[String]) -> Option<TagId> {
+        if path.len() <= 1 {
+            return None;
+        }
+        self.tag_paths.by_path.get(&path[..path.len() - 1].join("/")).copied()
+    }
+
+    /// Records `parent` for `tag`; a nested tag without one becomes an orphan
+    /// waiting for a tag at `parent_path`.
+    fn set_tag_parent(&mut self, tag: TagId, parent: Option<TagId>, parent_path: Option<&str>) {
+        self.tag_hierarchy.parent.insert(tag, parent);
+        match (parent, parent_path) {
+            (Some(parent), _) => {
+                self.tag_hierarchy.children.entry(parent).or_default().push(tag);
+            }
+            (None, Some(parent_path)) => {
+                self.tag_hierarchy.orphans.entry(parent_path.to_string()).or_default().insert(tag);
+            }
+            (None, None) => {}
+        }
+    }
+
+    /// Moves all children of `from` to `to` when `to` takes over its path.
+    fn transfer_children(&mut self, from: TagId, to: TagId) {
+        let children = self.tag_hierarchy.children.remove(&from).unwrap_or_default();
+        for child in &children {
+            self.tag_hierarchy.parent.insert(*child, Some(to));
+        }
+        if !children.is_empty() {
+            self.tag_hierarchy.children.entry(to).or_default().extend(children);
+        }
+
+        self.tag_membership.subtree_nodes.remove(&from);
+        self.refresh_subtree(to);
+    }
+
+    /// Proper ancestors of a tag, nearest first.
+    fn tag_ancestors(&self, tag: TagId) -> Vec<TagId> {
+        let mut ancestors = Vec::new();
+        let mut current = tag;
+        while let Some(Some(parent)) = self.tag_hierarchy.parent.get(&current) {
+            ancestors.push(*parent);
+            current = *parent;
+        }
+        ancestors
+    }
+
+    /// Recomputes the subtree bitmap of a tag from its children.
+    fn refresh_subtree(&mut self, tag: TagId) {
+        let mut bitmap = RoaringBitmap::new();
+        for child in self.tag_hierarchy.children.get(&tag).into_iter().flatten() {
+            if let Some(direct) = self.tag_membership.direct_nodes.get(child) {
+                bitmap |= direct;
+            }
+            if let Some(subtree) = self.tag_membership.subtree_nodes.get(child) {
+                bitmap |= subtree;
+            }
+        }
+
+        if bitmap.is_empty() {
+            self.tag_membership.subtree_nodes.remove(&tag);
+        } else {
+            self.tag_membership.subtree_nodes.insert(tag, bitmap);
+        }
+    }
+
+    fn refresh_subtree_upwards(&mut self, from: Option<TagId>) {
+        let mut current = from;
+        while let Some(tag) = current {
+            self.refresh_subtree(tag);
+            current = self.tag_hierarchy.parent.get(&tag).copied().flatten();
+        }
+    }
+
+    // ----------------------------
+    // Node operations
+    // ----------------------------
+
+    pub fn upsert_node(&mut self, node: NodeRecord) -> Result<NodeId, RepoError> {
+        self.logged(Operation::UpsertNode(node.clone()), |repo| {
+            let node_id = *node.get_id();
+            repo.write_node(node);
+
+            // the counter only moves forward, so replays end up with the same
+            // counter whether or not `get_next_node_id` was called first
+            repo.next_node_id = repo.next_node_id.max(NodeId(node_id.0 + 1));
+
+            Ok(node_id)
+        })
+    }
+
+    pub fn delete_node(&mut self, node: NodeId) -> Result<(), RepoError> {
+        self.logged(Operation::DeleteNode(node), |repo| {
+            let mut node = repo.nodes
+                .get(&node)
+                .cloned()
+                .ok_or_else(|| RepoError::NotFound("delete_node: node not found".to_string()))?;
+            node.deleted = true;
+
+            repo.write_node(node);
+            Ok(())
+        })
     }
 
     pub fn get_node(&self, node: NodeId) -> Option<&NodeRecord> {
         self.nodes.get(&node)
     }
 
+    /// Local id of the node with `uid`, deleted or not.
+    pub fn node_by_uid(&self, uid: Uid) -> Option<NodeId> {
+        self.uids.nodes.get(&uid).copied()
+    }
+
     pub fn iter_nodes(&self) -> impl Iterator<Item = &NodeRecord> {
         self.nodes.values().filter(|node| !node.deleted)
     }
@@ -191,36 +792,134 @@ impl Repository {
     // ----------------------------
 
     pub fn upsert_tag(&mut self, tag: TagRecord) -> Result<TagId, RepoError> {
-        let tag_id = *tag.get_id();
-        self.tags.insert(tag_id, tag);
-
-        if tag_id.0 == self.next_tag_id.0 {
-            self.next_tag_id.0 = tag_id.0 + 1;
-        }
+        self.logged(Operation::UpsertTag(tag.clone()), |repo| {
+            let tag_id = *tag.get_id();
+            repo.write_tag(tag);
 
-        self.rebuild_all_indexes();
+            // see `upsert_node`
+            repo.next_tag_id = repo.next_tag_id.max(TagId(tag_id.0 + 1));
 
-        Ok(tag_id)
+            Ok(tag_id)
+        })
     }
 
+    /// Deletes a tag, keeping node references so it can be restored. Its
+    /// descendants stay in place; see [`delete_tag_with`](Self::delete_tag_with)
+    /// to delete or move them as well.
     pub fn delete_tag(&mut self, tag: TagId) -> Result<(), RepoError> {
-        let tag = self.tags
-            .get_mut(&tag)
-            .ok_or_else(|| RepoError::NotFound("delete_tag: tag not found".to_string()))?;
-        tag.deleted = true;
+        self.delete_tag_with(tag, DeleteTagOptions::default())
+    }
 
-        self.rebuild_all_indexes();
-        Ok(())
+    /// Deletes a tag, handling its descendants and the nodes referencing it
+    /// as described by `options`. Deleting a tag already in the trash does
+    /// nothing.
+    ///
+    /// With [`ChildTagMode::Reparent`], the direct children move up one level
+    /// and are merged into any existing tag at their new path. Deleted tags no
+    /// longer count for membership queries whether or not their ids are
+    /// stripped from the nodes.
+    pub fn delete_tag_with(
+        &mut self,
+        tag: TagId,
+        options: DeleteTagOptions
+    ) -> Result<(), RepoError> {
+        self.logged(Operation::DeleteTag { tag, options }, |repo| {
+            let record = repo.tags
+                .get(&tag)
+                .ok_or_else(|| RepoError::NotFound("delete_tag: tag not found".to_string()))?;
+            // already in the trash; its descendants and nodes were handled then
+            if record.deleted {
+                return Ok(());
+            }
+            let path = record.get_path().clone();
+
+            let mut deleted = vec![tag];
+            let mut children = Vec::new();
+            match options.children {
+                ChildTagMode::Keep => {}
+                ChildTagMode::Cascade => {
+                    deleted.extend(
+                        repo
+                            .iter_tags()
+                            .filter(|t| t.get_path().len() > path.len())
+                            .filter(|t| t.get_path().starts_with(&path))
+                            .map(|t| *t.get_id())
+                    );
+                }
+                ChildTagMode::Reparent => {
+                    // children of an orphaned tag would have nowhere to go
+                    let parent_path = parent_path_of(&path);
+                    if parent_path.is_some_and(|p| !repo.tag_paths.by_path.contains_key(&p)) {
+                        return Err(
+                            RepoError::NotFound("delete_tag: parent tag not found".to_string())
+                        );
+                    }
+                    // moved in id order, so the result does not depend on index history
+                    children = repo.get_child_tags(tag).cloned().unwrap_or_default();
+                    children.sort();
+                }
+            }
+
+            // one timestamp for the whole cascade, so it can be restored together
+            let deleted_at = Some(repo.now());
+            for id in &deleted {
+                let mut record = repo.tags[id].clone();
+                record.deleted = true;
+                record.deleted_at = deleted_at;
+                repo.write_tag(record);
+            }
+
+            for child in children {
+                let Some(record) = repo.tags.get(&child).filter(|t| !t.deleted) else {
+                    continue;
+                };
+                // a tag with children has a non-empty path
+                let mut new_path: Vec<&str> = path[..path.len() - 1]
+                    .iter()
+                    .map(|s| s.as_str())
+                    .collect();
+                let name = record.get_path().last().cloned().unwrap_or_default();
+                new_path.push(&name);
+                let options = SetTagPathOptions {
+                    conflicts: PathConflictMode::Merge,
+                    ..SetTagPathOptions::default()
+                };
+                repo.set_tag_path_with(child, new_path, options)?;
+            }
+
+            if options.node_refs == NodeRefMode::Strip {
+                let stripped: Vec<NodeRecord> = repo.nodes
+                    .values()
+                    .filter(|node| node.tags.iter().any(|t| deleted.contains(t)))
+                    .cloned()
+                    .collect();
+                for mut node in stripped {
+                    node.tags.retain(|t| !deleted.contains(t));
+                    repo.write_node(node);
+                }
+            }
+
+            Ok(())
+        })
     }
 
     pub fn get_tag(&self, tag: TagId) -> Option<TagRecord> {
         self.tags.get(&tag).cloned()
     }
 
+    /// Local id of the tag with `uid`, deleted or not.
+    pub fn tag_by_uid(&self, uid: Uid) -> Option<TagId> {
+        self.uids.tags.get(&uid).copied()
+    }
+
     pub fn iter_tags(&self) -> impl Iterator<Item = &TagRecord> {
         self.tags.values().filter(|tag| !tag.deleted)
     }
 
+    pub fn is_live_tag(&self, tag: TagId) -> bool {
+        self.tags.get(&tag).is_some_and(|t| !t.deleted)
+    }
+
     pub fn get_next_tag_id(&mut self) -> TagId {
         // check if tag id is already used
         while self.tags.contains_key(&TagId(self.next_tag_id.0)) {
@@ -239,14 +938,161 @@ impl Repository {
         Err(RepoError::NotFound("get_tag_by_path: tag not found".to_string()))
     }
 
+    /// Returns the live tag at `path`, creating it and every missing ancestor
+    /// first if needed. Only a newly created leaf gets `color`; ancestors use
+    /// the default color.
+    pub fn ensure_tag_path(
+        &mut self,
+        path: &[&str],
+        color: Option<TagColors>
+    ) -> Result<TagId, RepoError> {
+        let path: Vec<String> = path
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        if !is_valid_tag_path(&path) {
+            return Err(RepoError::InvalidTagPath);
+        }
+
+        self.logged(Operation::EnsureTagPath { path: path.clone(), color }, |repo| {
+            let mut tag = None;
+            for len in 1..=path.len() {
+                let prefix = &path[..len];
+                if let Some(existing) = repo.tag_paths.by_path.get(&prefix.join("/")) {
+                    tag = Some(*existing);
+                    continue;
+                }
+
+                let id = repo.get_next_tag_id();
+                let color = if len == path.len() { color } else { None };
+                tag = Some(repo.upsert_tag(TagRecord::new(id, prefix.to_vec(), color))?);
+            }
+
+            Ok(tag.expect("path has at least one segment"))
+        })
+    }
+
     pub fn get_child_tags(&self, tag: TagId) -> Option<&Vec<TagId>> {
         self.tag_hierarchy.children.get(&tag)
     }
 
-    pub fn set_tag_path(&mut self, _tag: TagId, _new_path: Vec<&str>) -> Result<(), RepoError> {
-        todo!(
-            "update tag.path; dedup by path; then rebuild affected indexes (likely hierarchy + subtree)"
-        );
+    /// Renames or moves a tag together with all of its descendants, failing
+    /// with [`RepoError::PathConflict`] if any new path is already taken.
+    pub fn set_tag_path(&mut self, tag: TagId, new_path: Vec<&str>) -> Result<(), RepoError> {
+        self.set_tag_path_with(tag, new_path, SetTagPathOptions::default())
+    }
+
+    /// Renames or moves a tag together with all of its descendants.
+    ///
+    /// Every tag whose path starts with the tag's path is rewritten onto
+    /// `new_path`, trashed ones included so they are restored at the new
+    /// location. The parent of `new_path` must be a tag outside the moved
+    /// subtree; [`SetTagPathOptions::create_parents`] creates it if missing.
+    /// With [`PathConflictMode::Merge`], a moved tag whose new path is already
+    /// taken is merged into the existing tag: its nodes are retagged and the
+    /// moved tag is deleted.
+    pub fn set_tag_path_with(
+        &mut self,
+        tag: TagId,
+        new_path: Vec<&str>,
+        options: SetTagPathOptions
+    ) -> Result<(), RepoError> {
+        let new_path: Vec<String> = new_path
+            .into_iter()
+            .map(|s| s.to_string())
+            .collect();
+        let op = Operation::SetTagPath { tag, path: new_path.clone(), options };
+
+        self.logged(op, |repo| {
+            let old_path = repo.tags
+                .get(&tag)
+                .filter(|t| !t.deleted)
+                .ok_or_else(|| RepoError::NotFound("set_tag_path: tag not found".to_string()))?
+                .get_path()
+                .clone();
+
+            if new_path == old_path {
+                return Ok(());
+            }
+            // a tag cannot be moved below itself
+            if !is_valid_tag_path(&new_path) || new_path.starts_with(&old_path) {
+                return Err(RepoError::InvalidTagPath);
+            }
+
+            if new_path.len() > 1 {
+                let parent_path = &new_path[..new_path.len() - 1];
+                if options.create_parents {
+                    let parent_path: Vec<&str> = parent_path
+                        .iter()
+                        .map(|s| s.as_str())
+                        .collect();
+                    repo.ensure_tag_path(&parent_path, None)?;
+                } else if !repo.tag_paths.by_path.contains_key(&parent_path.join("/")) {
+                    return Err(
+                        RepoError::NotFound("set_tag_path: parent tag not found".to_string())
+                    );
+                }
+            }
+
+            // the tag and its descendants, shallowest first so parents merge first
+            let mut moved: Vec<(TagId, Vec<String>)> = repo.tags
+                .values()
+                .filter(|t| t.get_path().starts_with(&old_path))
+                .map(|t| {
+                    let mut path = new_path.clone();
+                    path.extend_from_slice(&t.get_path()[old_path.len()..]);
+                    (*t.get_id(), path)
+                })
+                .collect();
+            moved.sort_by_key(|(id, path)| (path.len(), id.0));
+
+            let moved_ids: HashSet<TagId> = moved
+                .iter()
+                .map(|(id, _)| *id)
+                .collect();
+
+            // trashed tags hold no path, so only live ones can conflict
+            let mut merges: Vec<(TagId, TagId)> = Vec::new();
+            for (id, path) in moved.iter().filter(|(id, _)| repo.is_live_tag(*id)) {
+                let path_str = path.join("/");
+                let existing = repo.tag_paths.by_path.get(&path_str).copied();
+                if let Some(existing) = existing.filter(|e| !moved_ids.contains(e)) {
+                    match options.conflicts {
+                        PathConflictMode::Fail => {
+                            return Err(RepoError::PathConflict(path_str));
+                        }
+                        PathConflictMode::Merge => merges.push((*id, existing)),
+                    }
+                }
+            }
+
+            for (id, path) in moved {
+                let mut record = repo.tags[&id].clone();
+                record.path = path;
+                repo.write_tag(record);
+            }
+
+            for (from, into) in merges {
+                let retagged: Vec<NodeRecord> = repo.nodes
+                    .values()
+                    .filter(|node| node.tags.contains(&from))
+                    .cloned()
+                    .collect();
+                for mut node in retagged {
+                    node.tags.retain(|t| *t != from);
+                    if !node.tags.contains(&into) {
+                        node.tags.push(into);
+                    }
+                    repo.write_node(node);
+                }
+
+                let mut record = repo.tags[&from].clone();
+                record.deleted = true;
+                repo.write_tag(record);
+            }
+
+            Ok(())
+        })
     }
 
     // ----------------------------
@@ -262,40 +1108,73 @@ impl Repository {
         DataBlob::from_data(store, data)
     }
 
+    /// Physical blobs of the live nodes tagged with any of `tags` or their
+    /// descendants, e.g. to pin them in a [`TieredBlobStore`].
+    ///
+    /// [`TieredBlobStore`]: crate::blob::tiered::TieredBlobStore
+    pub fn blobs_for_tags<S: crate::blob::BlobStore>(
+        &self,
+        store: &S,
+        tags: &[TagId]
+    ) -> Result<HashSet<BlobId>, BlobError>
+        where <S as crate::blob::BlobStore>::Error: std::fmt::Debug
+    {
+        let mut nodes = RoaringBitmap::new();
+        for tag in tags {
+            nodes |= self.tag_subtree_bitmap(*tag);
+        }
+
+        let mut blobs = HashSet::new();
+        for node in nodes.iter().filter_map(|id| self.nodes.get(&NodeId(id))) {
+            if node.deleted {
+                continue;
+            }
+            blobs.extend(node.data_ref.get_data_blob().physical_blobs(store)?);
+        }
+
+        Ok(blobs)
+    }
+
     // ----------------------------
     // Tagging operations
     // ----------------------------
 
     pub fn tag_node(&mut self, node: NodeId, tag: TagId) -> Result<(), RepoError> {
-        let node = self.nodes
-            .get_mut(&node)
-            .ok_or_else(|| RepoError::NotFound("tag_node: node not found".to_string()))?;
+        self.logged(Operation::TagNode { node, tag }, |repo| {
+            let node = repo.nodes
+                .get(&node)
+                .ok_or_else(|| RepoError::NotFound("tag_node: node not found".to_string()))?;
 
-        if !self.tags.contains_key(&tag) {
-            return Err(RepoError::NotFound("tag_node: tag not found".to_string()));
-        }
-
-        if !node.get_tags().contains(&tag) {
-            node.tags.push(tag);
-        }
+            if !repo.is_live_tag(tag) {
+                return Err(RepoError::NotFound("tag_node: tag not found".to_string()));
+            }
 
-        self.rebuild_all_indexes();
+            if !node.get_tags().contains(&tag) {
+                let mut node = node.clone();
+                node.tags.push(tag);
+                repo.write_node(node);
+            }
 
-        Ok(())
+            Ok(())
+        })
     }
 
     pub fn untag_node(&mut self, _node: NodeId, _tag: TagId) -> Result<(), RepoError> {
-        let node = self.nodes
-            .get_mut(&_node)
-            .ok_or(RepoError::NotFound("untag_node: node not found".to_string()))?;
-        if !self.tags.contains_key(&_tag) {
-            return Err(RepoError::NotFound("untag_node: tag not found".to_string()));
-        }
-
-        node.tags.retain(|t| *t != _tag);
+        self.logged(Operation::UntagNode { node: _node, tag: _tag }, |repo| {
+            let node = repo.nodes
+                .get(&_node)
+                .ok_or(RepoError::NotFound("untag_node: node not found".to_string()))?;
+            if !repo.tags.contains_key(&_tag) {
+                return Err(RepoError::NotFound("untag_node: tag not found".to_string()));
+            }
 
-        self.rebuild_all_indexes();
-        Ok(())
+            if node.get_tags().contains(&_tag) {
+                let mut node = node.clone();
+                node.tags.retain(|t| *t != _tag);
+                repo.write_node(node);
+            }
+            Ok(())
+        })
     }
 
     // ----------------------------
@@ -312,26 +1191,161 @@ impl Repository {
         }
     }
 
-    pub fn search_bitmap(&self, _query: TagQuery) -> Result<RoaringBitmap, RepoError> {
-        todo!("combine subtree bitmaps with union/intersection/difference");
+    /// Nodes tagged with the tag itself or any of its descendants.
+    pub(crate) fn tag_subtree_bitmap(&self, tag: TagId) -> RoaringBitmap {
+        let mut bitmap = self.tag_membership.direct_nodes.get(&tag).cloned().unwrap_or_default();
+        if let Some(subtree) = self.tag_membership.subtree_nodes.get(&tag) {
+            bitmap |= subtree;
+        }
+        bitmap
+    }
+
+    /// Evaluates a tag query to the bitmap of matching live nodes.
+    ///
+    /// `Tag(t)` matches nodes tagged with `t` or any of its descendants, and
+    /// `Not` is taken relative to all live nodes.
+    pub fn search_bitmap(&self, query: TagQuery) -> Result<RoaringBitmap, RepoError> {
+        self.eval_query(&query)
+    }
+
+    fn eval_query(&self, query: &TagQuery) -> Result<RoaringBitmap, RepoError> {
+        match query {
+            TagQuery::Tag(tag) => {
+                if !self.is_live_tag(*tag) {
+                    return Err(RepoError::NotFound("search_bitmap: tag not found".to_string()));
+                }
+                Ok(self.tag_subtree_bitmap(*tag))
+            }
+            TagQuery::Or(a, b) => Ok(self.eval_query(a)? | self.eval_query(b)?),
+            TagQuery::And(a, b) => Ok(self.eval_query(a)? & self.eval_query(b)?),
+            TagQuery::Not(q) => Ok(self.live_nodes_bitmap() - self.eval_query(q)?),
+        }
+    }
+
+    /// Live nodes created within `range`, to combine with tag query results.
+    pub fn nodes_created_in(&self, range: impl RangeBounds<DateTime<Utc>>) -> RoaringBitmap {
+        time_range_bitmap(&self.node_times.created, range)
+    }
+
+    /// Live nodes last updated within `range`.
+    pub fn nodes_updated_in(&self, range: impl RangeBounds<DateTime<Utc>>) -> RoaringBitmap {
+        time_range_bitmap(&self.node_times.updated, range)
+    }
+
+    pub fn live_nodes_bitmap(&self) -> RoaringBitmap {
+        self.iter_nodes()
+            .map(|node| node.get_id().0)
+            .collect()
     }
 
     pub fn node_ids_from_bitmap<'a>(
         &'a self,
-        _bm: &'a RoaringBitmap
+        bm: &'a RoaringBitmap
     ) -> impl Iterator<Item = NodeId> + 'a {
-        todo!("map NodeIx -> NodeId; skip deleted nodes as desired");
-        #[allow(unreachable_code)]
-        std::iter::empty::<NodeId>()
+        bm.iter()
+            .map(NodeId)
+            .filter(|id| self.nodes.get(id).is_some_and(|node| !node.deleted))
+    }
+}
+
+/// Indexes ids by uid, keeping the lowest id of a duplicated uid.
+fn lowest_id_per_uid<T: Copy + Ord>(entries: Vec<(Uid, T)>) -> HashMap<Uid, T> {
+    let mut index = HashMap::with_capacity(entries.len());
+    for (uid, id) in entries {
+        index
+            .entry(uid)
+            .and_modify(|lowest: &mut T| {
+                *lowest = (*lowest).min(id);
+            })
+            .or_insert(id);
+    }
+    index
+}
+
+/// Keeps the time a record went to the trash, stamping it on deletion and
+/// clearing it on restore.
+fn deletion_stamp(
+    deleted: bool,
+    deleted_at: Option<DateTime<Utc>>,
+    now: DateTime<Utc>
+) -> Option<DateTime<Utc>> {
+    if deleted { deleted_at.or(Some(now)) } else { None }
+}
+
+fn time_range_bitmap(
+    index: &BTreeSet<(DateTime<Utc>, NodeId)>,
+    range: impl RangeBounds<DateTime<Utc>>
+) -> RoaringBitmap {
+    let start = match range.start_bound() {
+        Bound::Included(t) => Bound::Included((*t, NodeId(0))),
+        Bound::Excluded(t) => Bound::Excluded((*t, NodeId(u32::MAX))),
+        Bound::Unbounded => Bound::Unbounded,
+    };
+    let end = match range.end_bound() {
+        Bound::Included(t) => Bound::Included((*t, NodeId(u32::MAX))),
+        Bound::Excluded(t) => Bound::Excluded((*t, NodeId(0))),
+        Bound::Unbounded => Bound::Unbounded,
+    };
+
+    // `BTreeSet::range` panics on inverted ranges
+    let inverted = match (&start, &end) {
+        (Bound::Included(s) | Bound::Excluded(s), Bound::Included(e) | Bound::Excluded(e)) => s > e,
+        _ => false,
+    };
+    if inverted {
+        return RoaringBitmap::new();
+    }
+
+    index
+        .range((start, end))
+        .map(|(_, node)| node.0)
+        .collect()
+}
+
+fn remove_bit(index: &mut HashMap<TagId, RoaringBitmap>, tag: TagId, node_ix: u32) {
+    if let Some(bitmap) = index.get_mut(&tag) {
+        bitmap.remove(node_ix);
+        if bitmap.is_empty() {
+            index.remove(&tag);
+        }
+    }
+}
+
+fn remove_child(children: &mut HashMap<TagId, Vec<TagId>>, parent: TagId, child: TagId) {
+    if let Some(ids) = children.get_mut(&parent) {
+        ids.retain(|id| *id != child);
+        if ids.is_empty() {
+            children.remove(&parent);
+        }
     }
 }
 
+fn remove_from_group(groups: &mut HashMap<String, BTreeSet<TagId>>, key: &str, id: TagId) {
+    if let Some(ids) = groups.get_mut(key) {
+        ids.remove(&id);
+        if ids.is_empty() {
+            groups.remove(key);
+        }
+    }
+}
+
+fn parent_path_of(path: &[String]) -> Option<String> {
+    (path.len() > 1).then(|| path[..path.len() - 1].join("/"))
+}
+
 impl Serialize for Repository {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: serde::Serializer {
-        let mut state = serializer.serialize_struct("Repository", 2)?;
-
-        state.serialize_field("nodes", &self.nodes).unwrap();
-        state.serialize_field("tags", &self.tags).unwrap();
+        let mut state = serializer.serialize_struct("Repository", 5)?;
+
+        // ordered by id, so equal repositories serialize to the same bytes
+        // and snapshots of them share a blob
+        let nodes: BTreeMap<_, _> = self.nodes.iter().collect();
+        let tags: BTreeMap<_, _> = self.tags.iter().collect();
+        state.serialize_field("nodes", &nodes).unwrap();
+        state.serialize_field("tags", &tags).unwrap();
+        state.serialize_field("last_stamp", &self.last_stamp).unwrap();
+        state.serialize_field("next_node_id", &self.next_node_id).unwrap();
+        state.serialize_field("next_tag_id", &self.next_tag_id).unwrap();
         state.end()
     }
 }
@@ -346,13 +1360,23 @@ impl<'de> Deserialize<'de> for Repository {
             tag_paths: Default::default(),
             tag_hierarchy: Default::default(),
             tag_membership: Default::default(),
-            next_node_id: NodeId(0),
-            next_tag_id: TagId(0),
+            node_times: Default::default(),
+            uids: Default::default(),
+            next_node_id: serde_repo.next_node_id,
+            next_tag_id: serde_repo.next_tag_id,
+            oplog: serde_repo.oplog,
+            history: Default::default(),
+            subscribers: Default::default(),
+            clock: None,
+            last_stamp: serde_repo.last_stamp,
+            operation_stamp: None,
+            indexes_deferred: false,
+            unrecognized_timestamps: BTreeSet::new(),
         })
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum TagQuery {
     Tag(TagId),
     Or(Box<TagQuery>, Box<TagQuery>),
@@ -360,12 +1384,114 @@ pub enum TagQuery {
     Not(Box<TagQuery>),
 }
 
+/// What [`Repository::set_tag_path_with`] does when a new path is taken.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    serde::Serialize,
+    serde::Deserialize
+)]
+pub enum PathConflictMode {
+    #[default]
+    Fail,
+    Merge,
+}
+
+/// How [`Repository::set_tag_path_with`] moves a tag.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    serde::Serialize,
+    serde::Deserialize
+)]
+pub struct SetTagPathOptions {
+    pub conflicts: PathConflictMode,
+    /// Create the new parent and its missing ancestors instead of failing.
+    pub create_parents: bool,
+}
+
+/// How [`Repository::delete_tag_with`] treats a tag's descendants and the
+/// nodes referencing it.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    serde::Serialize,
+    serde::Deserialize
+)]
+pub struct DeleteTagOptions {
+    pub children: ChildTagMode,
+    pub node_refs: NodeRefMode,
+}
+
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    serde::Serialize,
+    serde::Deserialize
+)]
+pub enum ChildTagMode {
+    /// Delete only the tag; its descendants stay live without a parent.
+    #[default]
+    Keep,
+    /// Delete all descendants along with the tag.
+    Cascade,
+    /// Move the direct children up to the deleted tag's parent.
+    Reparent,
+}
+
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    serde::Serialize,
+    serde::Deserialize
+)]
+pub enum NodeRefMode {
+    /// Leave the ids in `NodeRecord::tags`, so a restored tag gets its nodes back.
+    #[default]
+    Keep,
+    /// Remove the deleted tags from every node.
+    Strip,
+}
+
+/// A tag path needs at least one segment, and segments may neither be empty
+/// nor contain the `/` separator.
+pub(crate) fn is_valid_tag_path(path: &[String]) -> bool {
+    !path.is_empty() && path.iter().all(|segment| !segment.is_empty() && !segment.contains('/'))
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum RepoError {
     #[error("not found")] NotFound(String),
     #[error("invalid path")]
     InvalidTagPath,
+    #[error("tag path already exists: {0}")] PathConflict(String),
+    #[error("patch does not apply: {0}")] PatchConflict(String),
+    #[error("integrity issues: {0:?}")] Integrity(Vec<IntegrityIssue>),
+    #[error("root pointer moved by another writer")]
+    RootMoved,
     #[error("serialization error")]
     Serialization,
+    #[error(transparent)] Blob(#[from] BlobError),
+    #[error("io error: {0}")] Io(#[from] std::io::Error),
     #[error("other: {0}")] Other(String),
 }
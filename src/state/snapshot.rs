@@ -0,0 +1,172 @@
+//! Content-addressed history of repository states.
+//!
+//! [`Repository::save_snapshot`] writes the records into a [`BlobStore`] as
+//! a [`Snapshot`] pointing to the previous one and moves the store's
+//! [`RootPointer`] to it, so the snapshots form a chain like commits do.
+//! Snapshots are ordinary blobs next to the node data and are never
+//! modified; only the root pointer is. The records are written with the
+//! caller's [`BlobParams`], so with an [`EncryptionKey`] they are as private
+//! as the node data; the snapshot headers, holding only the chain, the
+//! saving actor and blob references, stay readable without the key.
+//!
+//! [`Repository::save_to_json`] stays the store-less way of saving and
+//! does not snapshot; saving into a store is what `save_snapshot` does.
+
+use std::{ collections::BTreeSet, convert::Infallible };
+
+use chrono::{ DateTime, Utc };
+
+use crate::{
+    blob::{ BlobError, BlobId, BlobParams, BlobStore, DataBlob, EncryptionKey, RootPointer },
+    state::{ oplog::Operation, repository::{ RepoError, Repository } },
+};
+
+/// A saved repository state. Its blob id identifies the snapshot.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Snapshot {
+    /// The snapshot the root pointed to when this one was saved.
+    pub parent: Option<BlobId>,
+    pub created: DateTime<Utc>,
+    pub actor: String,
+
+    /// Last [`OpLog`](crate::state::oplog::OpLog) entry covered.
+    pub seq: u64,
+
+    /// The repository's records, as written by [`Repository::save_to_json`]
+    /// without the operation log.
+    pub repository: DataBlob,
+}
+
+impl Snapshot {
+    pub fn load<S: BlobStore>(store: &S, id: &BlobId) -> Result<Self, RepoError>
+        where <S as BlobStore>::Error: std::fmt::Debug
+    {
+        let data = store.download(id).map_err(|e| BlobError::StoreError(format!("{:?}", e)))?;
+        if !id.verify(&data) {
+            return Err(BlobError::IntegrityCheckFailed.into());
+        }
+
+        serde_json::from_slice(&data).map_err(|_| RepoError::Serialization)
+    }
+}
+
+impl Repository {
+    /// Saves the records as a new snapshot on top of the store's root and
+    /// points the root at it. Returns the snapshot id, or the current root if
+    /// nothing changed since it was saved.
+    ///
+    /// Fails with [`RepoError::RootMoved`] if another writer saved a snapshot
+    /// in the meantime; the root is left at theirs, so merge their state and
+    /// save again.
+    pub fn save_snapshot<S: RootPointer>(
+        &self,
+        store: &mut S,
+        params: &BlobParams
+    ) -> Result<BlobId, RepoError>
+        where <S as BlobStore>::Error: std::fmt::Debug
+    {
+        let parent = read_root(store)?;
+        let data = self.records_to_json()?;
+        let repository = DataBlob::from_data_with_params(store, data.as_bytes(), params)?;
+
+        if let Some(parent) = &parent
+            && Snapshot::load(store, parent)?.repository == repository
+        {
+            return Ok(parent.clone());
+        }
+
+        let snapshot = Snapshot {
+            parent,
+            created: self.now(),
+            actor: self.oplog.actor().to_string(),
+            seq: self.oplog.last_seq(),
+            repository,
+        };
+        let header = serde_json::to_vec(&snapshot).map_err(|_| RepoError::Serialization)?;
+        let id = BlobId::from_data(params.hash_algorithm, &header);
+        store.upload(&id, &header).map_err(|e| BlobError::StoreError(format!("{:?}", e)))?;
+        let moved = !store
+            .write_root(snapshot.parent.as_ref(), &id)
+            .map_err(|e| BlobError::StoreError(format!("{:?}", e)))?;
+        if moved {
+            return Err(RepoError::RootMoved);
+        }
+
+        Ok(id)
+    }
+
+    /// Snapshots reachable from the store's root, newest first.
+    pub fn snapshots<S: RootPointer>(store: &S) -> Result<Vec<(BlobId, Snapshot)>, RepoError>
+        where <S as BlobStore>::Error: std::fmt::Debug
+    {
+        let mut snapshots = Vec::new();
+        let mut next = read_root(store)?;
+        while let Some(id) = next {
+            let snapshot = Snapshot::load(store, &id)?;
+            next = snapshot.parent.clone();
+            snapshots.push((id, snapshot));
+        }
+        Ok(snapshots)
+    }
+
+    /// The repository as saved in a snapshot, with an empty log and history.
+    /// `key` decrypts snapshots saved with encryption.
+    pub fn checkout<S: BlobStore>(
+        store: &S,
+        snapshot: &BlobId,
+        key: Option<&EncryptionKey>
+    ) -> Result<Repository, RepoError>
+        where <S as BlobStore>::Error: std::fmt::Debug
+    {
+        let snapshot = Snapshot::load(store, snapshot)?;
+        let data = snapshot.repository.retrieve_data_with_key(store, key)?;
+        let json = String::from_utf8(data).map_err(|_| RepoError::Serialization)?;
+        Repository::load_from_json(&json)
+    }
+
+    /// Puts every record back as it was in a snapshot, removing records
+    /// created since.
+    ///
+    /// Unlike [`checkout`](Self::checkout) this is an edit of this
+    /// repository: it is logged, undoable and wins over the reverted changes
+    /// when merging. Save a snapshot afterwards to record it in the chain.
+    pub fn roll_back_to_snapshot<S: BlobStore>(
+        &mut self,
+        store: &S,
+        snapshot: &BlobId,
+        key: Option<&EncryptionKey>
+    ) -> Result<(), RepoError>
+        where <S as BlobStore>::Error: std::fmt::Debug
+    {
+        let target = Self::checkout(store, snapshot, key)?;
+
+        let node_ids: BTreeSet<_> = self.nodes.keys().chain(target.nodes.keys()).collect();
+        let nodes: Vec<_> = node_ids
+            .into_iter()
+            .filter(|id| self.nodes.get(id) != target.nodes.get(id))
+            .map(|id| (*id, target.nodes.get(id).cloned()))
+            .collect();
+        let tag_ids: BTreeSet<_> = self.tags.keys().chain(target.tags.keys()).collect();
+        let tags: Vec<_> = tag_ids
+            .into_iter()
+            .filter(|id| self.tags.get(id) != target.tags.get(id))
+            .map(|id| (*id, target.tags.get(id).cloned()))
+            .collect();
+        if nodes.is_empty() && tags.is_empty() {
+            return Ok(());
+        }
+
+        let op = Operation::Revert { nodes: nodes.clone(), tags: tags.clone() };
+        let Ok(()) = self.logged(op, |repo| {
+            repo.revert(nodes, tags);
+            Ok::<_, Infallible>(())
+        });
+        Ok(())
+    }
+}
+
+fn read_root<S: RootPointer>(store: &S) -> Result<Option<BlobId>, RepoError>
+    where <S as BlobStore>::Error: std::fmt::Debug
+{
+    store.read_root().map_err(|e| BlobError::StoreError(format!("{:?}", e)).into())
+}
@@ -0,0 +1,599 @@
+//! Three-way merging with explicit conflicts.
+//!
+//! An alternative to [`Repository::merge`] for repositories that should not
+//! merge silently: changes made on one side since a common base are taken
+//! over, and changes both sides made to the same field are handed to a
+//! resolver. Records are matched by [`Uid`], as in [`Repository::diff`].
+
+use std::collections::{ BTreeMap, BTreeSet, HashMap, HashSet };
+
+use crate::{
+    node_type::NodeType,
+    state::{
+        diff::{
+            NodeDiff,
+            NodeState,
+            RepoDiff,
+            TagDiff,
+            TagState,
+            field_change,
+            node_state,
+            tag_state,
+        },
+        repository::{ RepoError, Repository },
+    },
+    tag::TagColors,
+    uid::Uid,
+};
+
+/// A side of a three-way merge.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub enum Side {
+    Ours,
+    Theirs,
+}
+
+/// Changes of both sides that cannot be merged. `base` is `None` for records
+/// both sides added.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum Conflict {
+    /// Both sides changed a node's data differently, e.g. its title.
+    NodeData {
+        node: Uid,
+        base: Option<Box<NodeType>>,
+        ours: Box<NodeType>,
+        theirs: Box<NodeType>,
+    },
+    /// One side deleted or removed a node the other side changed.
+    NodeDeleted {
+        node: Uid,
+        deleted_by: Side,
+    },
+    /// Both sides renamed or moved a tag to different paths.
+    TagPath {
+        tag: Uid,
+        base: Option<Vec<String>>,
+        ours: Vec<String>,
+        theirs: Vec<String>,
+    },
+    TagColor {
+        tag: Uid,
+        base: Option<TagColors>,
+        ours: TagColors,
+        theirs: TagColors,
+    },
+    /// One side deleted or removed a tag the other side changed, e.g. moved.
+    TagDeleted {
+        tag: Uid,
+        deleted_by: Side,
+    },
+    /// Two live tags would end up at one path, e.g. both sides added `a/b`
+    /// or renamed different tags to it. `ours` is the tag our side has at
+    /// the path (or else any tag of ours); `theirs` is one the merge brings
+    /// there.
+    TagPathCollision {
+        path: Vec<String>,
+        ours: Uid,
+        theirs: Uid,
+    },
+}
+
+/// How a [`Conflict`] is settled.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum Resolution {
+    /// Keep our side's value. For a [`Conflict::TagPathCollision`], the
+    /// `theirs` tag is not added or moved there.
+    Ours,
+    /// Take their side's value. For a [`Conflict::TagPathCollision`], the
+    /// `ours` tag gives way: it is not added or moved there, or deleted if it
+    /// already was there.
+    Theirs,
+    /// A path of its own for the tag of a [`Conflict::TagPath`] or the
+    /// `theirs` tag of a [`Conflict::TagPathCollision`].
+    Path(Vec<String>),
+    /// Data of its own for the node of a [`Conflict::NodeData`].
+    Data(Box<NodeType>),
+    /// A color of its own for the tag of a [`Conflict::TagColor`].
+    Color(TagColors),
+}
+
+impl From<Side> for Resolution {
+    fn from(side: Side) -> Self {
+        match side {
+            Side::Ours => Resolution::Ours,
+            Side::Theirs => Resolution::Theirs,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ResolvedConflict {
+    pub conflict: Conflict,
+    pub resolution: Resolution,
+}
+
+/// Outcome of [`Repository::merge_three_way`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ThreeWayReport {
+    /// Changes applied to this repository.
+    pub patch: RepoDiff,
+    pub conflicts: Vec<ResolvedConflict>,
+}
+
+impl Repository {
+    /// Merges the changes `theirs` made since `base` into this repository,
+    /// which descends from `base` as well.
+    ///
+    /// Fields only one side changed take that side's value, and tags added
+    /// to or removed from a node on either side are added or removed. Every
+    /// other difference is a [`Conflict`], which `resolve` settles by picking
+    /// the side whose value to keep or giving a value of its own; it is
+    /// called once per conflict: tags in uid order, then tags colliding on a
+    /// path in path order, then nodes in uid order. The result is applied as
+    /// one [`apply_diff`](Self::apply_diff) patch, so it is logged and
+    /// undoable. Run it on a clone to preview the conflicts.
+    ///
+    /// Fails with [`RepoError::PatchConflict`] if a resolution does not fit
+    /// its conflict, e.g. a path for a node, and with
+    /// [`RepoError::PathConflict`] if a path given by `resolve` is taken.
+    pub fn merge_three_way(
+        &mut self,
+        base: &Repository,
+        theirs: &Repository,
+        mut resolve: impl FnMut(&Conflict) -> Resolution
+    ) -> Result<ThreeWayReport, RepoError> {
+        let mut patch = RepoDiff::default();
+        let mut conflicts = Vec::new();
+        let mut resolve = |conflict: Conflict| {
+            let resolution = resolve(&conflict);
+            conflicts.push(ResolvedConflict { conflict, resolution: resolution.clone() });
+            resolution
+        };
+
+        let tags = [base, self, theirs].map(tag_states);
+        let uids: BTreeSet<&Uid> = tags.iter().flat_map(|states| states.keys()).collect();
+        for uid in uids {
+            let [b, o, t] = [0, 1, 2].map(|side| tags[side].get(uid));
+            merge_tag(&mut patch, b, o, t, &mut resolve)?;
+        }
+        resolve_path_collisions(&mut patch, &tags[1], &mut resolve)?;
+
+        // nodes can only keep or gain tags the merged repository still has
+        let mut live_tags: HashSet<Uid> = tags[1].keys().copied().collect();
+        live_tags.extend(patch.tags_added.iter().map(|tag| tag.uid));
+        for tag in &patch.tags_removed {
+            live_tags.remove(&tag.uid);
+        }
+
+        let nodes = [base, self, theirs].map(node_states);
+        let uids: BTreeSet<&Uid> = nodes.iter().flat_map(|states| states.keys()).collect();
+        for uid in uids {
+            let [b, o, t] = [0, 1, 2].map(|side| nodes[side].get(uid));
+            merge_node(&mut patch, b, o, t, &live_tags, &mut resolve)?;
+        }
+
+        patch.sort();
+        if !patch.is_empty() {
+            self.apply_diff(&patch)?;
+        }
+        Ok(ThreeWayReport { patch, conflicts })
+    }
+}
+
+fn tag_states(repo: &Repository) -> HashMap<Uid, TagState> {
+    repo.uids.tags
+        .iter()
+        .map(|(uid, id)| (*uid, tag_state(*uid, &repo.tags[id])))
+        .collect()
+}
+
+fn node_states(repo: &Repository) -> HashMap<Uid, NodeState> {
+    repo.uids.nodes
+        .iter()
+        .map(|(uid, id)| (*uid, node_state(repo, *uid, &repo.nodes[id])))
+        .collect()
+}
+
+fn merge_tag(
+    patch: &mut RepoDiff,
+    base: Option<&TagState>,
+    ours: Option<&TagState>,
+    theirs: Option<&TagState>,
+    resolve: &mut impl FnMut(Conflict) -> Resolution
+) -> Result<(), RepoError> {
+    let same = |a: Option<&TagState>, b: Option<&TagState>| {
+        a.map(|a| (&a.path, a.color, a.deleted)) == b.map(|b| (&b.path, b.color, b.deleted))
+    };
+    if same(theirs, base) {
+        return Ok(());
+    }
+
+    match (ours, theirs) {
+        (None, None) => {}
+        (None, Some(theirs)) => {
+            let take = same(ours, base) || {
+                let conflict = Conflict::TagDeleted { tag: theirs.uid, deleted_by: Side::Ours };
+                side(resolve(conflict))? == Side::Theirs
+            };
+            if take {
+                patch.tags_added.push(theirs.clone());
+            }
+        }
+        (Some(ours), None) => {
+            let take = same(Some(ours), base) || {
+                let conflict = Conflict::TagDeleted { tag: ours.uid, deleted_by: Side::Theirs };
+                side(resolve(conflict))? == Side::Theirs
+            };
+            if take {
+                patch.tags_removed.push(ours.clone());
+            }
+        }
+        (Some(ours), Some(theirs)) => {
+            let tag = ours.uid;
+            let path = merge_field(
+                base.map(|b| &b.path),
+                &ours.path,
+                &theirs.path,
+                resolve,
+                || Conflict::TagPath {
+                    tag,
+                    base: base.map(|b| b.path.clone()),
+                    ours: ours.path.clone(),
+                    theirs: theirs.path.clone(),
+                },
+                |resolution| match resolution {
+                    Resolution::Path(path) => Some(path),
+                    _ => None,
+                }
+            )?;
+            let base_color = base.map(|b| &b.color);
+            let color = merge_field(
+                base_color,
+                &ours.color,
+                &theirs.color,
+                resolve,
+                || Conflict::TagColor {
+                    tag,
+                    base: base.map(|b| b.color),
+                    ours: ours.color,
+                    theirs: theirs.color,
+                },
+                |resolution| match resolution {
+                    Resolution::Color(color) => Some(color),
+                    _ => None,
+                }
+            )?;
+            let edited = |side: &TagState| {
+                base.is_none_or(|b| b.path != side.path || b.color != side.color)
+            };
+            let deleted = merge_deleted(
+                base.map(|b| b.deleted),
+                [(ours.deleted, edited(ours)), (theirs.deleted, edited(theirs))],
+                resolve,
+                |deleted_by| Conflict::TagDeleted { tag, deleted_by }
+            )?;
+
+            let diff = TagDiff {
+                uid: tag,
+                id: ours.id,
+                path: field_change(&ours.path, &path),
+                color: field_change(&ours.color, &color),
+                deleted: field_change(&ours.deleted, &deleted),
+            };
+            if diff.path.is_some() || diff.color.is_some() || diff.deleted.is_some() {
+                patch.tags_modified.push(diff);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Settles tags that would share a path once `patch` is applied to `ours`.
+fn resolve_path_collisions(
+    patch: &mut RepoDiff,
+    ours: &HashMap<Uid, TagState>,
+    resolve: &mut impl FnMut(Conflict) -> Resolution
+) -> Result<(), RepoError> {
+    for (path, mut tags) in merged_tag_paths(ours, patch) {
+        if tags.len() < 2 {
+            continue;
+        }
+
+        // our tag at the path keeps it, then any other tag of ours
+        tags.sort_by_key(|uid| {
+            let ours = ours.get(uid);
+            (ours.is_none(), ours.is_none_or(|tag| tag.deleted || tag.path != path), *uid)
+        });
+        let kept = tags[0];
+        for tag in tags.into_iter().skip(1) {
+            let conflict = Conflict::TagPathCollision {
+                path: path.clone(),
+                ours: kept,
+                theirs: tag,
+            };
+            match resolve(conflict) {
+                Resolution::Ours => give_way(patch, ours, tag),
+                Resolution::Theirs => give_way(patch, ours, kept),
+                Resolution::Path(path) => set_path(patch, ours, tag, path),
+                resolution => {
+                    return Err(unfit(&resolution));
+                }
+            }
+        }
+    }
+
+    patch.tags_modified.retain(|change| {
+        change.path.is_some() || change.color.is_some() || change.deleted.is_some()
+    });
+    Ok(())
+}
+
+/// The live tags by path after applying `patch` to `ours`, placed like
+/// [`Repository::apply_diff`] does: a moved live tag takes the tags below
+/// its old path along.
+fn merged_tag_paths(
+    ours: &HashMap<Uid, TagState>,
+    patch: &RepoDiff
+) -> BTreeMap<Vec<String>, Vec<Uid>> {
+    let mut tags: HashMap<Uid, (Vec<String>, bool)> = ours
+        .iter()
+        .map(|(uid, tag)| (*uid, (tag.path.clone(), tag.deleted)))
+        .collect();
+    for removed in &patch.tags_removed {
+        tags.remove(&removed.uid);
+    }
+
+    let mut moves = Vec::new();
+    for change in &patch.tags_modified {
+        let Some((_, deleted)) = tags.get_mut(&change.uid) else {
+            continue;
+        };
+        if let Some(change) = &change.deleted {
+            *deleted = change.after;
+        }
+        if let Some(path) = &change.path {
+            moves.push((path.before.len(), change.uid, path.after.clone()));
+        }
+    }
+    moves.sort();
+    for (_, moved, new_path) in moves {
+        let (old_path, deleted) = tags[&moved].clone();
+        for (uid, (path, _)) in tags.iter_mut() {
+            if *uid == moved || (!deleted && path.starts_with(&old_path)) {
+                *path = [&new_path[..], &path[old_path.len()..]].concat();
+            }
+        }
+    }
+
+    tags.extend(patch.tags_added.iter().map(|tag| (tag.uid, (tag.path.clone(), tag.deleted))));
+    let mut by_path: BTreeMap<Vec<String>, Vec<Uid>> = BTreeMap::new();
+    for (uid, (path, deleted)) in tags {
+        if !deleted {
+            by_path.entry(path).or_default().push(uid);
+        }
+    }
+    by_path
+}
+
+/// Keeps a tag off the path it collides on: an added tag is left out, a
+/// moved one stays where it is on our side and any other one is deleted.
+fn give_way(patch: &mut RepoDiff, ours: &HashMap<Uid, TagState>, tag: Uid) {
+    if let Some(index) = patch.tags_added.iter().position(|added| added.uid == tag) {
+        patch.tags_added.remove(index);
+        return;
+    }
+
+    let ours = &ours[&tag];
+    match patch.tags_modified.iter_mut().find(|change| change.uid == tag) {
+        Some(change) if change.path.is_some() => {
+            change.path = None;
+        }
+        Some(change) => {
+            change.deleted = field_change(&ours.deleted, &true);
+        }
+        None => {
+            patch.tags_modified.push(TagDiff {
+                uid: tag,
+                id: ours.id,
+                path: None,
+                color: None,
+                deleted: field_change(&ours.deleted, &true),
+            });
+        }
+    }
+}
+
+/// Puts a tag at `path` instead of where the merge would.
+fn set_path(patch: &mut RepoDiff, ours: &HashMap<Uid, TagState>, tag: Uid, path: Vec<String>) {
+    if let Some(added) = patch.tags_added.iter_mut().find(|added| added.uid == tag) {
+        added.path = path;
+        return;
+    }
+
+    let ours = &ours[&tag];
+    match patch.tags_modified.iter_mut().find(|change| change.uid == tag) {
+        Some(change) => {
+            change.path = field_change(&ours.path, &path);
+        }
+        None => {
+            patch.tags_modified.push(TagDiff {
+                uid: tag,
+                id: ours.id,
+                path: field_change(&ours.path, &path),
+                color: None,
+                deleted: None,
+            });
+        }
+    }
+}
+
+fn merge_node(
+    patch: &mut RepoDiff,
+    base: Option<&NodeState>,
+    ours: Option<&NodeState>,
+    theirs: Option<&NodeState>,
+    live_tags: &HashSet<Uid>,
+    resolve: &mut impl FnMut(Conflict) -> Resolution
+) -> Result<(), RepoError> {
+    let same = |a: Option<&NodeState>, b: Option<&NodeState>| {
+        a.map(|a| (&a.data, a.deleted, &a.tags)) == b.map(|b| (&b.data, b.deleted, &b.tags))
+    };
+    if same(theirs, base) {
+        return Ok(());
+    }
+
+    match (ours, theirs) {
+        (None, None) => {}
+        (None, Some(theirs)) => {
+            let take = same(ours, base) || {
+                let conflict = Conflict::NodeDeleted { node: theirs.uid, deleted_by: Side::Ours };
+                side(resolve(conflict))? == Side::Theirs
+            };
+            if take {
+                let mut added = theirs.clone();
+                added.tags.retain(|tag| live_tags.contains(tag));
+                patch.nodes_added.push(added);
+            }
+        }
+        (Some(ours), None) => {
+            let take = same(Some(ours), base) || {
+                let conflict = Conflict::NodeDeleted { node: ours.uid, deleted_by: Side::Theirs };
+                side(resolve(conflict))? == Side::Theirs
+            };
+            if take {
+                patch.nodes_removed.push(ours.clone());
+            }
+        }
+        (Some(ours), Some(theirs)) => {
+            let node = ours.uid;
+            let data = merge_field(
+                base.map(|b| &b.data),
+                &ours.data,
+                &theirs.data,
+                resolve,
+                || Conflict::NodeData {
+                    node,
+                    base: base.map(|b| Box::new(b.data.clone())),
+                    ours: Box::new(ours.data.clone()),
+                    theirs: Box::new(theirs.data.clone()),
+                },
+                |resolution| match resolution {
+                    Resolution::Data(data) => Some(*data),
+                    _ => None,
+                }
+            )?;
+            let edited = |side: &NodeState| {
+                base.is_none_or(|b| b.data != side.data || b.tags != side.tags)
+            };
+            let deleted = merge_deleted(
+                base.map(|b| b.deleted),
+                [(ours.deleted, edited(ours)), (theirs.deleted, edited(theirs))],
+                resolve,
+                |deleted_by| Conflict::NodeDeleted { node, deleted_by }
+            )?;
+
+            // tags the other side did not touch stay as they are on ours
+            let base_tags = base.map_or(&[][..], |b| &b.tags[..]);
+            let diff = NodeDiff {
+                uid: node,
+                id: ours.id,
+                data: field_change(&ours.data, &data),
+                deleted: field_change(&ours.deleted, &deleted),
+                tags_added: theirs.tags
+                    .iter()
+                    .filter(|tag| !base_tags.contains(tag) && !ours.tags.contains(tag))
+                    .filter(|tag| live_tags.contains(tag))
+                    .copied()
+                    .collect(),
+                tags_removed: base_tags
+                    .iter()
+                    .filter(|tag| !theirs.tags.contains(tag) && ours.tags.contains(tag))
+                    .copied()
+                    .collect(),
+            };
+            let changed =
+                diff.data.is_some() ||
+                diff.deleted.is_some() ||
+                !diff.tags_added.is_empty() ||
+                !diff.tags_removed.is_empty();
+            if changed {
+                patch.nodes_modified.push(diff);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The merged value of a field: the value of the side that changed it, or
+/// the one `resolve` picks or gives, via `custom`, if both did.
+fn merge_field<T: Clone + PartialEq>(
+    base: Option<&T>,
+    ours: &T,
+    theirs: &T,
+    resolve: &mut impl FnMut(Conflict) -> Resolution,
+    conflict: impl FnOnce() -> Conflict,
+    custom: impl FnOnce(Resolution) -> Option<T>
+) -> Result<T, RepoError> {
+    if ours == theirs || base == Some(theirs) {
+        return Ok(ours.clone());
+    } else if base == Some(ours) {
+        return Ok(theirs.clone());
+    }
+
+    match resolve(conflict()) {
+        Resolution::Ours => Ok(ours.clone()),
+        Resolution::Theirs => Ok(theirs.clone()),
+        resolution => {
+            let unfit = unfit(&resolution);
+            custom(resolution).ok_or(unfit)
+        }
+    }
+}
+
+/// The merged deletion state, given each side's state and whether the side
+/// edited the record's other fields. Deleting a record the other side edited
+/// is a conflict; restoring one is not.
+fn merge_deleted(
+    base: Option<bool>,
+    [(ours, ours_edited), (theirs, theirs_edited)]: [(bool, bool); 2],
+    resolve: &mut impl FnMut(Conflict) -> Resolution,
+    conflict: impl FnOnce(Side) -> Conflict
+) -> Result<bool, RepoError> {
+    if ours == theirs {
+        return Ok(ours);
+    }
+
+    let deleted_by = if ours { Side::Ours } else { Side::Theirs };
+    let other_edited = match deleted_by {
+        Side::Ours => theirs_edited,
+        Side::Theirs => ours_edited,
+    };
+    match base {
+        // one side restored it
+        Some(true) => {
+            return Ok(false);
+        }
+        Some(false) if !other_edited => {
+            return Ok(true);
+        }
+        _ => {}
+    }
+
+    match side(resolve(conflict(deleted_by)))? {
+        Side::Ours => Ok(ours),
+        Side::Theirs => Ok(theirs),
+    }
+}
+
+/// The side a resolution picks, for conflicts without values of their own.
+fn side(resolution: Resolution) -> Result<Side, RepoError> {
+    match resolution {
+        Resolution::Ours => Ok(Side::Ours),
+        Resolution::Theirs => Ok(Side::Theirs),
+        resolution => Err(unfit(&resolution)),
+    }
+}
+
+fn unfit(resolution: &Resolution) -> RepoError {
+    RepoError::PatchConflict(format!("resolution {resolution:?} does not fit its conflict"))
+}
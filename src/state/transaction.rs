@@ -0,0 +1,231 @@
+use std::collections::{ HashMap, HashSet };
+
+use crate::{
+    node::{ NodeId, NodeRecord },
+    state::{
+        history::Change,
+        oplog::Operation,
+        repository::{
+            DeleteTagOptions,
+            RepoError,
+            Repository,
+            SetTagPathOptions,
+            is_valid_tag_path,
+        },
+    },
+    tag::{ TagColors, TagId, TagRecord },
+};
+
+/// Edits against a [`Repository`] that take effect together, created by
+/// [`Repository::transaction`].
+///
+/// Each edit goes through the repository method of the same name and is
+/// applied right away, so reads see it. If the transaction fails, every edit
+/// is rolled back like a failed mutation.
+pub struct Transaction<'a> {
+    repo: &'a mut Repository,
+
+    /// Operations run so far, logged as one [`Operation::Transaction`].
+    ops: Vec<Operation>,
+}
+
+impl Transaction<'_> {
+    /// Runs an edit, recording `op` if it succeeds.
+    fn run<T>(
+        &mut self,
+        op: Operation,
+        f: impl FnOnce(&mut Repository) -> Result<T, RepoError>
+    ) -> Result<T, RepoError> {
+        let value = f(self.repo)?;
+        self.ops.push(op);
+        Ok(value)
+    }
+
+    // ----------------------------
+    // Node operations
+    // ----------------------------
+
+    pub fn get_node(&self, node: NodeId) -> Option<&NodeRecord> {
+        self.repo.get_node(node)
+    }
+
+    pub fn upsert_node(&mut self, node: NodeRecord) -> Result<NodeId, RepoError> {
+        self.run(Operation::UpsertNode(node.clone()), |repo| repo.upsert_node(node))
+    }
+
+    pub fn delete_node(&mut self, node: NodeId) -> Result<(), RepoError> {
+        self.run(Operation::DeleteNode(node), |repo| repo.delete_node(node))
+    }
+
+    pub fn get_next_node_id(&mut self) -> NodeId {
+        self.repo.get_next_node_id()
+    }
+
+    // ----------------------------
+    // Tag operations
+    // ----------------------------
+
+    pub fn get_tag(&self, tag: TagId) -> Option<&TagRecord> {
+        self.repo.tags.get(&tag)
+    }
+
+    pub fn upsert_tag(&mut self, tag: TagRecord) -> Result<TagId, RepoError> {
+        self.run(Operation::UpsertTag(tag.clone()), |repo| repo.upsert_tag(tag))
+    }
+
+    /// Like [`Repository::delete_tag`].
+    pub fn delete_tag(&mut self, tag: TagId) -> Result<(), RepoError> {
+        self.delete_tag_with(tag, DeleteTagOptions::default())
+    }
+
+    /// Like [`Repository::delete_tag_with`].
+    pub fn delete_tag_with(
+        &mut self,
+        tag: TagId,
+        options: DeleteTagOptions
+    ) -> Result<(), RepoError> {
+        self.run(Operation::DeleteTag { tag, options }, |repo| repo.delete_tag_with(tag, options))
+    }
+
+    pub fn get_next_tag_id(&mut self) -> TagId {
+        self.repo.get_next_tag_id()
+    }
+
+    pub fn get_tag_by_path(&self, path: Vec<String>) -> Result<TagId, RepoError> {
+        self.repo.tag_paths.by_path
+            .get(&path.join("/"))
+            .copied()
+            .ok_or_else(|| RepoError::NotFound("get_tag_by_path: tag not found".to_string()))
+    }
+
+    /// Like [`Repository::ensure_tag_path`].
+    pub fn ensure_tag_path(
+        &mut self,
+        path: &[&str],
+        color: Option<TagColors>
+    ) -> Result<TagId, RepoError> {
+        let op = Operation::EnsureTagPath {
+            path: path
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+            color,
+        };
+        self.run(op, |repo| repo.ensure_tag_path(path, color))
+    }
+
+    /// Like [`Repository::set_tag_path`].
+    pub fn set_tag_path(&mut self, tag: TagId, new_path: Vec<&str>) -> Result<(), RepoError> {
+        self.set_tag_path_with(tag, new_path, SetTagPathOptions::default())
+    }
+
+    /// Like [`Repository::set_tag_path_with`].
+    pub fn set_tag_path_with(
+        &mut self,
+        tag: TagId,
+        new_path: Vec<&str>,
+        options: SetTagPathOptions
+    ) -> Result<(), RepoError> {
+        let op = Operation::SetTagPath {
+            tag,
+            path: new_path
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+            options,
+        };
+        self.run(op, |repo| repo.set_tag_path_with(tag, new_path, options))
+    }
+
+    // ----------------------------
+    // Tagging operations
+    // ----------------------------
+
+    pub fn tag_node(&mut self, node: NodeId, tag: TagId) -> Result<(), RepoError> {
+        self.run(Operation::TagNode { node, tag }, |repo| repo.tag_node(node, tag))
+    }
+
+    pub fn untag_node(&mut self, node: NodeId, tag: TagId) -> Result<(), RepoError> {
+        self.run(Operation::UntagNode { node, tag }, |repo| repo.untag_node(node, tag))
+    }
+}
+
+impl Repository {
+    /// Runs `f` as one mutation: its edits are logged and undone as a unit,
+    /// and all of them are rolled back if it fails.
+    ///
+    /// Fails, changing nothing, if `f` returns an error, if a tag it wrote
+    /// has an invalid path or shares its path with another live tag, or if a
+    /// node it wrote gained a reference to a missing or deleted tag.
+    ///
+    /// ```ignore
+    /// repo.transaction(|tx| {
+    ///     let tag = tx.get_next_tag_id();
+    ///     tx.upsert_tag(TagRecord::new(tag, vec!["inbox".to_string()], None))?;
+    ///     tx.tag_node(node, tag)
+    /// })?;
+    /// ```
+    pub fn transaction<T, F>(&mut self, f: F) -> Result<T, RepoError>
+        where F: FnOnce(&mut Transaction<'_>) -> Result<T, RepoError>
+    {
+        self.logged_with(|repo| {
+            let mark = repo.history.mark();
+            let mut tx = Transaction { repo, ops: Vec::new() };
+            let value = f(&mut tx)?;
+            let ops = tx.ops;
+
+            repo.validate_changes_since(mark)?;
+            Ok((value, Operation::Transaction(ops)))
+        })
+    }
+
+    /// Checks the records written since the history `mark`, see
+    /// [`transaction`](Self::transaction).
+    fn validate_changes_since(&self, mark: usize) -> Result<(), RepoError> {
+        // the first change to a record holds its version from before the mark
+        let mut nodes_before: HashMap<NodeId, Option<&NodeRecord>> = HashMap::new();
+        let mut tags = HashSet::new();
+        for change in self.history.changes_since(mark) {
+            match change {
+                Change::Node(id, before) => {
+                    nodes_before.entry(*id).or_insert(before.as_ref());
+                }
+                Change::Tag(id, _) => {
+                    tags.insert(*id);
+                }
+            }
+        }
+
+        for tag in tags.iter().filter_map(|id| self.tags.get(id)).filter(|t| !t.deleted) {
+            if !is_valid_tag_path(tag.get_path()) {
+                return Err(RepoError::InvalidTagPath);
+            }
+            let path = tag.get_path().join("/");
+            if self.tag_paths.shadowed.get(&path).is_some_and(|shadowed| !shadowed.is_empty()) {
+                return Err(RepoError::PathConflict(path));
+            }
+        }
+
+        for (id, before) in nodes_before {
+            let Some(node) = self.nodes.get(&id).filter(|n| !n.deleted) else {
+                continue;
+            };
+            // references kept from before, e.g. to a tag trashed without
+            // stripping its nodes, are left alone
+            let gained_dead_tag = node
+                .get_tags()
+                .iter()
+                .filter(|tag| before.is_none_or(|before| !before.get_tags().contains(tag)))
+                .any(|tag| !self.is_live_tag(*tag));
+            if gained_dead_tag {
+                let message = format!(
+                    "transaction: node {} references a missing or deleted tag",
+                    id.0
+                );
+                return Err(RepoError::NotFound(message));
+            }
+        }
+
+        Ok(())
+    }
+}
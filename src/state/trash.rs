@@ -0,0 +1,176 @@
+use std::{ cmp::Reverse, collections::HashSet, convert::Infallible };
+
+use chrono::{ DateTime, Duration, Utc };
+
+use crate::{
+    node::NodeId,
+    state::{ oplog::Operation, repository::{ RepoError, Repository, is_valid_tag_path } },
+    tag::TagId,
+};
+
+/// A deleted record, as listed by [`Repository::trash`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct TrashEntry {
+    pub item: TrashItem,
+
+    /// `None` for records deleted before deletion times were kept.
+    pub deleted_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub enum TrashItem {
+    Node(NodeId),
+    Tag(TagId),
+}
+
+/// Records physically removed by [`Repository::empty_trash`] or
+/// [`Repository::purge_trash`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct PurgeReport {
+    pub nodes: Vec<NodeId>,
+    pub tags: Vec<TagId>,
+}
+
+impl Repository {
+    /// Every deleted node and tag, most recently deleted first.
+    pub fn trash(&self) -> Vec<TrashEntry> {
+        let nodes = self.nodes
+            .values()
+            .filter(|node| node.deleted)
+            .map(|node| TrashEntry {
+                item: TrashItem::Node(*node.get_id()),
+                deleted_at: *node.get_deleted_at(),
+            });
+        let tags = self.tags
+            .values()
+            .filter(|tag| tag.deleted)
+            .map(|tag| TrashEntry {
+                item: TrashItem::Tag(*tag.get_id()),
+                deleted_at: *tag.get_deleted_at(),
+            });
+
+        let mut entries: Vec<TrashEntry> = nodes.chain(tags).collect();
+        entries.sort_by_key(|entry| Reverse(entry.deleted_at));
+        entries
+    }
+
+    /// Takes a node out of the trash. It rejoins every live tag it still
+    /// references.
+    pub fn restore_node(&mut self, node: NodeId) -> Result<(), RepoError> {
+        self.logged(Operation::RestoreNode(node), |repo| {
+            let mut node = repo.nodes
+                .get(&node)
+                .filter(|n| n.deleted)
+                .cloned()
+                .ok_or_else(|| RepoError::NotFound("restore_node: node not in trash".to_string()))?;
+            node.deleted = false;
+
+            repo.write_node(node);
+            Ok(())
+        })
+    }
+
+    /// Takes a tag out of the trash, together with the descendants deleted in
+    /// the same cascade. Nodes that kept their references get the tags back.
+    ///
+    /// Fails with [`RepoError::InvalidTagPath`] for a tag without a valid
+    /// path, if the parent tag is not live, or with
+    /// [`RepoError::PathConflict`] if a live tag has since taken one of the
+    /// paths.
+    pub fn restore_tag(&mut self, tag: TagId) -> Result<(), RepoError> {
+        self.logged(Operation::RestoreTag(tag), |repo| {
+            let record = repo.tags
+                .get(&tag)
+                .filter(|t| t.deleted)
+                .ok_or_else(|| RepoError::NotFound("restore_tag: tag not in trash".to_string()))?;
+            let path = record.get_path().clone();
+            let deleted_at = *record.get_deleted_at();
+            if !is_valid_tag_path(&path) {
+                return Err(RepoError::InvalidTagPath);
+            }
+
+            let parent_path = path[..path.len() - 1].join("/");
+            if path.len() > 1 && !repo.tag_paths.by_path.contains_key(&parent_path) {
+                return Err(RepoError::NotFound("restore_tag: parent tag not found".to_string()));
+            }
+
+            let mut restored: Vec<(TagId, usize)> = vec![(tag, path.len())];
+            if deleted_at.is_some() {
+                restored.extend(
+                    repo.tags
+                        .values()
+                        .filter(|t| t.deleted && *t.get_id() != tag)
+                        .filter(|t| *t.get_deleted_at() == deleted_at)
+                        .filter(|t| t.get_path().starts_with(&path))
+                        .map(|t| (*t.get_id(), t.get_path().len()))
+                );
+            }
+            // parents first, and the lowest id wins if the cascade held duplicates
+            restored.sort_by_key(|(id, depth)| (*depth, id.0));
+            let mut seen = HashSet::new();
+            restored.retain(|(id, _)| seen.insert(repo.tags[id].get_path().clone()));
+
+            for (id, _) in &restored {
+                let path = repo.tags[id].get_path().join("/");
+                if repo.tag_paths.by_path.contains_key(&path) {
+                    return Err(RepoError::PathConflict(path));
+                }
+            }
+
+            for (id, _) in restored {
+                let mut record = repo.tags[&id].clone();
+                record.deleted = false;
+                repo.write_tag(record);
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Physically removes every deleted node and tag.
+    pub fn empty_trash(&mut self) -> PurgeReport {
+        let Ok(report) = self.logged(Operation::EmptyTrash, |repo| {
+            Ok::<_, Infallible>(repo.purge_where(|_| true))
+        });
+        report
+    }
+
+    /// Physically removes records that have been in the trash for longer than
+    /// `retention`. Records without a deletion time are kept.
+    pub fn purge_trash(&mut self, retention: Duration) -> PurgeReport {
+        self.purge_deleted_before(Utc::now() - retention)
+    }
+
+    /// Physically removes records deleted before `cutoff`. Records without a
+    /// deletion time are kept.
+    pub fn purge_deleted_before(&mut self, cutoff: DateTime<Utc>) -> PurgeReport {
+        let Ok(report) = self.logged(Operation::PurgeDeletedBefore(cutoff), |repo| {
+            Ok::<_, Infallible>(repo.purge_where(|deleted_at| deleted_at.is_some_and(|at| at < cutoff)))
+        });
+        report
+    }
+
+    fn purge_where(&mut self, eligible: impl Fn(Option<DateTime<Utc>>) -> bool) -> PurgeReport {
+        let mut report = PurgeReport::default();
+
+        for entry in self.trash() {
+            if !eligible(entry.deleted_at) {
+                continue;
+            }
+            match entry.item {
+                TrashItem::Node(node) => {
+                    self.erase_node(node);
+                    report.nodes.push(node);
+                }
+                TrashItem::Tag(tag) => {
+                    self.erase_tag(tag);
+                    report.tags.push(tag);
+                }
+            }
+        }
+
+        report.nodes.sort();
+        report.tags.sort();
+        report
+    }
+}
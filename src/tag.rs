@@ -1,9 +1,24 @@
-use std::{ collections::HashMap, fmt::Debug, str::FromStr };
+use std::{ collections::{ BTreeSet, HashMap }, fmt::Debug, str::FromStr };
 
+use chrono::{ DateTime, Utc };
 use getset::Getters;
 use roaring::RoaringBitmap;
 
-#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+use crate::{ hlc::Hlc, uid::Uid };
+
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    serde::Serialize,
+    serde::Deserialize
+)]
 pub struct TagId(pub u32);
 
 impl From<u32> for TagId {
@@ -51,25 +66,53 @@ impl From<TagColors> for &str {
     }
 }
 
-#[derive(Clone, Debug, Getters, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Getters, serde::Serialize, serde::Deserialize)]
 #[getset(get = "pub with_prefix")]
 pub struct TagRecord {
-    id: TagId,
+    pub(crate) id: TagId,
+
+    /// Identity across repositories, see [`Uid`]. Left nil, the repository
+    /// assigns one when the record is first stored.
+    #[serde(default)]
+    pub(crate) uid: Uid,
 
     pub(crate) deleted: bool,
 
-    path: Vec<String>,
+    /// When the record was moved to the trash; `None` for live records and
+    /// for records deleted before timestamps were kept.
+    #[serde(default)]
+    pub(crate) deleted_at: Option<DateTime<Utc>>,
+
+    pub(crate) path: Vec<String>,
+
+    pub(crate) color: TagColors,
 
-    color: TagColors,
+    /// When each field was last written, for merging.
+    #[serde(default)]
+    pub(crate) clock: TagClock,
+}
+
+/// Last-writer-wins stamps of a tag's fields. Records written before merging
+/// was supported carry zero stamps and lose to any later write.
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct TagClock {
+    pub path: Hlc,
+    pub color: Hlc,
+
+    /// Covers `deleted_at` as well.
+    pub deleted: Hlc,
 }
 
 impl TagRecord {
     pub fn new(id: TagId, path: Vec<String>, color: Option<TagColors>) -> Self {
         Self {
             id,
+            uid: Uid::NIL,
             deleted: false,
+            deleted_at: None,
             path,
             color: color.unwrap_or(TagColors::Gray),
+            clock: TagClock::default(),
         }
     }
 }
@@ -80,12 +123,19 @@ pub struct TagHierarchyIndex {
     pub parent: HashMap<TagId, Option<TagId>>,
 
     pub children: HashMap<TagId, Vec<TagId>>,
+
+    /// Nested tags whose parent path has no live tag, by that parent path.
+    pub orphans: HashMap<String, BTreeSet<TagId>>,
 }
 
 /// Derived tag lookup index (rebuildable from tags[*].path).
 #[derive(Clone, Debug, Default)]
 pub struct TagPathIndex {
+    /// The live tag owning each path, the lowest id if several share it.
     pub by_path: HashMap<String, TagId>,
+
+    /// The other live tags sharing a path, which take over in id order.
+    pub shadowed: HashMap<String, BTreeSet<TagId>>,
 }
 
 /// Derived membership indexes (rebuildable from nodes[*].tags and hierarchy).
@@ -96,4 +146,8 @@ pub struct TagMembershipIndex {
 
     /// Nodes that contain this tag OR any descendant tag.
     pub subtree_nodes: HashMap<TagId, RoaringBitmap>,
+
+    /// Nodes that still reference a deleted (or missing) tag. These count
+    /// for no query and move back to `direct_nodes` if the tag is restored.
+    pub dormant_nodes: HashMap<TagId, RoaringBitmap>,
 }
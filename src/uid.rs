@@ -0,0 +1,147 @@
+//! Globally unique record identities.
+//!
+//! [`NodeId`](crate::node::NodeId) and [`TagId`](crate::tag::TagId) are dense
+//! local numbers that keep the membership bitmaps small; two devices creating
+//! records offline hand out the same ones. A [`Uid`] identifies a record
+//! across repositories instead, and is what merging matches records by.
+
+use std::{ collections::HashMap, fmt, str::FromStr };
+
+use chrono::{ DateTime, Utc };
+use uuid::{ Builder, Uuid };
+
+use crate::{ hlc::Hlc, node::{ NodeId, NodeRecord }, tag::{ TagId, TagRecord } };
+
+/// A UUIDv7: the creation time in milliseconds followed by bits unique to the
+/// record, so uids sort roughly by creation.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    serde::Serialize,
+    serde::Deserialize
+)]
+#[serde(transparent)]
+pub struct Uid(Uuid);
+
+/// The kind of record a uid is derived for, so a node and a tag with the same
+/// local id never share one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum UidKind {
+    Node,
+    Tag,
+}
+
+impl Uid {
+    /// Placeholder of records that get their uid when first stored.
+    pub const NIL: Uid = Uid(Uuid::nil());
+
+    pub fn is_nil(&self) -> bool {
+        self.0.is_nil()
+    }
+
+    pub fn as_uuid(&self) -> &Uuid {
+        &self.0
+    }
+
+    /// The uid of a record created by the operation stamped `stamp`.
+    ///
+    /// Derived rather than random so replaying the operation log reproduces
+    /// it; the replica in the stamp keeps uids of different devices apart.
+    pub(crate) fn derived(stamp: Hlc, kind: UidKind, local: u32) -> Self {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&[kind as u8]);
+        hasher.update(&stamp.replica.to_le_bytes());
+        hasher.update(&stamp.counter.to_le_bytes());
+        hasher.update(&stamp.millis.to_le_bytes());
+        hasher.update(&local.to_le_bytes());
+        Self::build(stamp.millis.max(0) as u64, hasher.finalize())
+    }
+
+    /// The uid of a node from an archive written before uids were kept.
+    ///
+    /// Derived from the local id and the creation time, which later writes
+    /// keep: copies of one archive migrate to the same uids and still merge
+    /// record by record, while nodes that devices created independently under
+    /// the same id stay apart.
+    pub(crate) fn legacy_node(local: NodeId, created: DateTime<Utc>) -> Self {
+        let mut origin = created.timestamp_millis().to_le_bytes().to_vec();
+        origin.extend(created.timestamp_subsec_nanos().to_le_bytes());
+        Self::legacy(UidKind::Node, local.0, &origin)
+    }
+
+    /// The uid of a tag from an archive written before uids were kept.
+    ///
+    /// Tags have no creation time, so the path stands in for it: tags of
+    /// different devices only share a uid when they also share the id and
+    /// the path. A tag renamed in one copy of an archive before it was
+    /// migrated merges as a separate tag.
+    pub(crate) fn legacy_tag(local: TagId, path: &[String]) -> Self {
+        Self::legacy(UidKind::Tag, local.0, path.join("/").as_bytes())
+    }
+
+    fn legacy(kind: UidKind, local: u32, origin: &[u8]) -> Self {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(b"legacy");
+        hasher.update(&[kind as u8]);
+        hasher.update(&local.to_le_bytes());
+        hasher.update(origin);
+        Self::build(0, hasher.finalize())
+    }
+
+    /// The uid of `node` stored under `local`, or its legacy uid if it has none.
+    pub(crate) fn of_node(local: NodeId, node: &NodeRecord) -> Self {
+        if node.uid.is_nil() { Self::legacy_node(local, node.date_created) } else { node.uid }
+    }
+
+    /// The uid of `tag` stored under `local`, or its legacy uid if it has none.
+    pub(crate) fn of_tag(local: TagId, tag: &TagRecord) -> Self {
+        if tag.uid.is_nil() { Self::legacy_tag(local, &tag.path) } else { tag.uid }
+    }
+
+    /// A replacement for this uid, for the `nth` further record of one
+    /// repository carrying it.
+    pub(crate) fn distinct(self, nth: u32) -> Self {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(b"distinct");
+        hasher.update(self.0.as_bytes());
+        hasher.update(&nth.to_le_bytes());
+        let millis = self.0.get_timestamp().map_or(0, |ts| {
+            let (secs, nanos) = ts.to_unix();
+            secs * 1000 + u64::from(nanos) / 1_000_000
+        });
+        Self::build(millis, hasher.finalize())
+    }
+
+    fn build(millis: u64, hash: blake3::Hash) -> Self {
+        let bytes: &[u8; 10] = hash.as_bytes()[..10].try_into().expect("hash has 32 bytes");
+        Self(Builder::from_unix_timestamp_millis(millis, bytes).into_uuid())
+    }
+}
+
+impl fmt::Display for Uid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl FromStr for Uid {
+    type Err = uuid::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Uuid::parse_str(s).map(Self)
+    }
+}
+
+/// Derived uid lookup (rebuildable from nodes[*].uid and tags[*].uid).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UidIndex {
+    pub nodes: HashMap<Uid, NodeId>,
+    pub tags: HashMap<Uid, TagId>,
+}
@@ -0,0 +1,51 @@
+//! Fixtures shared by the integration tests.
+
+// each test crate uses its own subset
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+
+use archivum_core::{
+    blob::{ BlobId, BlobStore },
+    node::{ NodeId, NodeRecord },
+    node_type::{ Bookmark, NodeType },
+    state::repository::Repository,
+    tag::{ TagId, TagRecord },
+};
+use smallvec::smallvec;
+
+#[derive(Default)]
+pub struct InMemoryStore(HashMap<BlobId, Vec<u8>>);
+
+impl BlobStore for InMemoryStore {
+    type Error = String;
+    fn upload(&mut self, id: &BlobId, data: &[u8]) -> Result<(), Self::Error> {
+        self.0.insert(id.clone(), data.to_vec());
+        Ok(())
+    }
+    fn download(&self, id: &BlobId) -> Result<Vec<u8>, Self::Error> {
+        self.0
+            .get(id)
+            .cloned()
+            .ok_or_else(|| "Blob not found".to_string())
+    }
+}
+
+/// Bookmark data for `url`, uploaded to `store`.
+pub fn bookmark(repo: &mut Repository, store: &mut InMemoryStore, url: &str) -> NodeType {
+    let blob = repo.upload_data(store, url.as_bytes()).unwrap();
+    NodeType::Bookmark(Bookmark::new(blob, None))
+}
+
+pub fn add_bookmark(repo: &mut Repository, store: &mut InMemoryStore, url: &str) -> NodeId {
+    let data = bookmark(repo, store, url);
+    let id = repo.get_next_node_id();
+    repo.upsert_node(NodeRecord::new(id, data, smallvec![])).unwrap()
+}
+
+/// Adds a tag at a slash-separated `path`, without creating its parents.
+pub fn add_tag(repo: &mut Repository, path: &str) -> TagId {
+    let path = path.split('/').map(str::to_string).collect();
+    let id = repo.get_next_tag_id();
+    repo.upsert_tag(TagRecord::new(id, path, None)).unwrap()
+}
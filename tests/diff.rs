@@ -0,0 +1,110 @@
+mod common;
+
+use archivum_core::{
+    node::NodeRecord,
+    state::{ diff::FieldChange, repository::{ RepoError, Repository } },
+    tag::{ TagColors, TagId, TagRecord },
+};
+use smallvec::smallvec;
+
+use common::{ InMemoryStore, add_bookmark, add_tag, bookmark };
+
+fn paths(repo: &Repository) -> Vec<String> {
+    let mut paths: Vec<String> = repo
+        .iter_tags()
+        .map(|tag| tag.get_path().join("/"))
+        .collect();
+    paths.sort();
+    paths
+}
+
+fn tag_at(repo: &mut Repository, path: &str) -> TagId {
+    repo.get_tag_by_path(path.split('/').map(str::to_string).collect()).unwrap()
+}
+
+#[test]
+fn a_diff_applied_to_its_source_gives_its_target() {
+    let mut store = InMemoryStore::default();
+    let mut a = Repository::new();
+    let docs = add_tag(&mut a, "docs");
+    let old = add_tag(&mut a, "docs/old");
+    let kept = add_bookmark(&mut a, &mut store, "https://a.example");
+    let trashed = add_bookmark(&mut a, &mut store, "https://b.example");
+    a.tag_node(kept, old).unwrap();
+
+    let mut b = a.clone();
+    b.set_actor("b");
+    b.set_tag_path(docs, vec!["papers"]).unwrap();
+    let id = b.get_next_tag_id();
+    b.upsert_tag(TagRecord::new(id, vec!["inbox".to_string()], Some(TagColors::Red))).unwrap();
+    b.untag_node(kept, old).unwrap();
+    b.tag_node(kept, id).unwrap();
+    let data = bookmark(&mut b, &mut store, "https://c.example");
+    b.upsert_node(NodeRecord::new(kept, data, smallvec![id])).unwrap();
+    b.delete_node(trashed).unwrap();
+    add_bookmark(&mut b, &mut store, "https://d.example");
+
+    let diff = a.diff(&b);
+    assert!(!diff.is_empty());
+    a.apply_diff(&diff).unwrap();
+    assert!(a.diff(&b).is_empty());
+    assert_eq!(paths(&a), ["inbox", "papers", "papers/old"]);
+    a.check_index_consistency().unwrap();
+
+    // the patch is a single undo step
+    assert!(a.undo());
+    assert_eq!(paths(&a), ["docs", "docs/old"]);
+}
+
+#[test]
+fn patched_paths_are_validated() {
+    let mut a = Repository::new();
+    add_tag(&mut a, "docs");
+    let mut b = a.clone();
+    b.set_actor("b");
+    add_tag(&mut b, "inbox");
+
+    let mut diff = a.diff(&b);
+    diff.tags_added[0].path = vec!["in/box".to_string()];
+    let before = a.clone();
+    assert!(matches!(a.apply_diff(&diff), Err(RepoError::InvalidTagPath)));
+    assert_eq!(a.tags, before.tags);
+
+    let mut b = a.clone();
+    let docs = tag_at(&mut b, "docs");
+    b.set_tag_path(docs, vec!["papers"]).unwrap();
+    let mut diff = a.diff(&b);
+    diff.tags_modified[0].path = Some(FieldChange {
+        before: vec!["docs".to_string()],
+        after: vec![String::new()],
+    });
+    assert!(matches!(a.apply_diff(&diff), Err(RepoError::InvalidTagPath)));
+    assert_eq!(a.tags, before.tags);
+    assert_eq!(a.oplog().entries().len(), before.oplog().entries().len());
+}
+
+#[test]
+fn patched_moves_take_descendants_along() {
+    let mut a = Repository::new();
+    let docs = add_tag(&mut a, "docs");
+    let mut b = a.clone();
+    b.set_tag_path(docs, vec!["papers"]).unwrap();
+    let diff = a.diff(&b);
+
+    // a child created after the diff was taken moves as well
+    let mut target = a.clone();
+    target.set_actor("target");
+    let child = add_tag(&mut target, "docs/2024");
+    target.apply_diff(&diff).unwrap();
+    assert_eq!(paths(&target), ["papers", "papers/2024"]);
+    assert_eq!(tag_at(&mut target, "papers/2024"), child);
+    target.check_index_consistency().unwrap();
+
+    // the new path is taken here, so nothing moves
+    let mut taken = a.clone();
+    taken.set_actor("taken");
+    add_tag(&mut taken, "papers");
+    let before = taken.tags.clone();
+    assert!(matches!(taken.apply_diff(&diff), Err(RepoError::PathConflict(_))));
+    assert_eq!(taken.tags, before);
+}
@@ -0,0 +1,109 @@
+mod common;
+
+use archivum_core::{ node::NodeId, state::repository::{ RepoError, Repository }, tag::TagId };
+
+use common::{ InMemoryStore, add_bookmark, add_tag };
+
+fn paths(repo: &Repository) -> Vec<String> {
+    let mut paths: Vec<String> = repo
+        .iter_tags()
+        .map(|tag| tag.get_path().join("/"))
+        .collect();
+    paths.sort();
+    paths
+}
+
+fn tags_of(repo: &Repository, node: NodeId) -> Vec<TagId> {
+    repo.get_node(node).map_or(Vec::new(), |node| node.get_tags().to_vec())
+}
+
+#[test]
+fn undo_and_redo_step_through_edits() {
+    let mut store = InMemoryStore::default();
+    let mut repo = Repository::new();
+    let tag = add_tag(&mut repo, "a");
+    let node = add_bookmark(&mut repo, &mut store, "https://a.example");
+    repo.tag_node(node, tag).unwrap();
+    repo.set_tag_path(tag, vec!["b"]).unwrap();
+
+    assert!(repo.undo());
+    assert_eq!(paths(&repo), ["a"]);
+    assert!(repo.undo());
+    assert!(tags_of(&repo, node).is_empty());
+    assert!(repo.can_redo());
+
+    assert!(repo.redo());
+    assert_eq!(tags_of(&repo, node), [tag]);
+    assert!(repo.redo());
+    assert_eq!(paths(&repo), ["b"]);
+    assert!(!repo.redo());
+
+    // back to the empty repository, one step per edit
+    for _ in 0..4 {
+        assert!(repo.undo());
+    }
+    assert!(!repo.undo());
+    assert!(!repo.can_undo());
+    assert!(repo.nodes.is_empty() && repo.tags.is_empty());
+    repo.check_index_consistency().unwrap();
+}
+
+#[test]
+fn grouped_edits_undo_as_one_step() {
+    let mut store = InMemoryStore::default();
+    let mut repo = Repository::new();
+    let node = add_bookmark(&mut repo, &mut store, "https://a.example");
+
+    repo.undo_group(|repo| {
+        let tag = add_tag(repo, "a");
+        add_tag(repo, "a/b");
+        repo.tag_node(node, tag)
+    }).unwrap();
+    assert_eq!(paths(&repo), ["a", "a/b"]);
+
+    assert!(repo.undo());
+    assert!(paths(&repo).is_empty());
+    assert!(tags_of(&repo, node).is_empty());
+    assert!(repo.redo());
+    assert_eq!(paths(&repo), ["a", "a/b"]);
+    assert_eq!(tags_of(&repo, node).len(), 1);
+
+    // a failed group is reverted and leaves no step behind
+    let result = repo.undo_group(|repo| {
+        add_tag(repo, "c");
+        Err::<(), _>(RepoError::NotFound("stop".to_string()))
+    });
+    assert!(result.is_err());
+    assert_eq!(paths(&repo), ["a", "a/b"]);
+    assert!(repo.undo());
+    assert!(paths(&repo).is_empty());
+    repo.check_index_consistency().unwrap();
+}
+
+#[test]
+fn a_new_edit_clears_redo() {
+    let mut repo = Repository::new();
+    add_tag(&mut repo, "a");
+    add_tag(&mut repo, "b");
+
+    assert!(repo.undo());
+    assert!(repo.can_redo());
+    add_tag(&mut repo, "c");
+    assert!(!repo.can_redo());
+    assert!(!repo.redo());
+    assert_eq!(paths(&repo), ["a", "c"]);
+}
+
+#[test]
+fn the_undo_limit_drops_the_oldest_steps() {
+    let mut repo = Repository::new();
+    for path in ["a", "b", "c"] {
+        add_tag(&mut repo, path);
+    }
+
+    repo.set_undo_limit(2);
+    assert!(repo.undo());
+    assert!(repo.undo());
+    assert!(!repo.undo());
+    assert_eq!(paths(&repo), ["a"]);
+}
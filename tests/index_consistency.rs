@@ -0,0 +1,71 @@
+mod common;
+
+use archivum_core::state::repository::{ Repository, SetTagPathOptions };
+
+use common::{ InMemoryStore, add_bookmark, add_tag };
+
+fn assert_consistent(repo: &Repository, step: &str) {
+    if let Err(e) = repo.check_index_consistency() {
+        panic!("indexes diverged after {step}: {e}");
+    }
+}
+
+#[test]
+fn incremental_indexes_match_rebuild() {
+    let mut repo = Repository::new();
+    let mut store = InMemoryStore::default();
+
+    let a = add_bookmark(&mut repo, &mut store, "https://a.example");
+    let b = add_bookmark(&mut repo, &mut store, "https://b.example");
+    let c = add_bookmark(&mut repo, &mut store, "https://c.example");
+
+    // orphans first, then the parents that adopt them
+    let deep = add_tag(&mut repo, "media/photos/2024");
+    let photos = add_tag(&mut repo, "media/photos");
+    assert_consistent(&repo, "adding orphans");
+    let media = repo.ensure_tag_path(&["media"], None).unwrap();
+    assert_consistent(&repo, "adopting orphans");
+
+    // duplicate paths: the lowest id owns the path, the others are shadowed
+    let dup = add_tag(&mut repo, "media/photos");
+    let dup_child = add_tag(&mut repo, "media/photos/raw");
+    assert_consistent(&repo, "adding duplicate paths");
+
+    repo.tag_node(a, deep).unwrap();
+    repo.tag_node(b, photos).unwrap();
+    repo.tag_node(c, dup).unwrap();
+    repo.tag_node(c, media).unwrap();
+    assert_consistent(&repo, "tagging nodes");
+
+    // the owner leaves, so the shadowed duplicate takes over its children
+    repo.delete_tag(photos).unwrap();
+    assert_consistent(&repo, "deleting a path owner");
+    repo.restore_tag(photos).unwrap_err();
+    assert!(repo.undo());
+    assert_consistent(&repo, "undoing a deletion");
+
+    repo.set_tag_path(dup, vec!["archive", "photos"]).unwrap_err();
+    let options = SetTagPathOptions { create_parents: true, ..Default::default() };
+    repo.set_tag_path_with(dup, vec!["archive", "photos"], options).unwrap();
+    assert_consistent(&repo, "moving a duplicate");
+
+    repo.set_tag_path(media, vec!["library"]).unwrap();
+    assert_consistent(&repo, "renaming a root tag");
+
+    repo.untag_node(b, photos).unwrap();
+    repo.delete_node(a).unwrap();
+    assert_consistent(&repo, "untagging and deleting nodes");
+    repo.restore_node(a).unwrap();
+    assert_consistent(&repo, "restoring a node");
+
+    repo.delete_tag(dup_child).unwrap();
+    repo.empty_trash();
+    assert_consistent(&repo, "emptying the trash");
+
+    while repo.undo() {
+        assert_consistent(&repo, "undo");
+    }
+    while repo.redo() {
+        assert_consistent(&repo, "redo");
+    }
+}
@@ -0,0 +1,95 @@
+mod common;
+
+use archivum_core::{
+    blob::{ BlobId, BlobStore },
+    node::NodeId,
+    state::repository::{ Repository, TagQuery },
+    tag::TagId,
+};
+use chrono::{ TimeZone, Utc };
+
+use common::InMemoryStore;
+
+const URL: &[u8] = b"https://example.com";
+
+/// A repository as saved before blob ids were multihashes: ids are bare
+/// blake3 hashes, serialized as byte arrays, and records have neither uids
+/// nor merge stamps.
+const LEGACY_REPOSITORY: &str =
+    r#"{
+  "nodes": {
+    "0": {
+      "id": 0,
+      "deleted": false,
+      "data_ref": {
+        "Bookmark": {
+          "data_ref": {
+            "Single": {
+              "blob": [56, 167, 131, 56, 88, 53, 61, 134, 18, 113, 195, 148, 252, 1, 31, 90,
+                       76, 237, 80, 193, 177, 96, 34, 165, 2, 108, 153, 205, 81, 15, 141, 35],
+              "metadata": {
+                "original_size": 19
+              }
+            }
+          },
+          "title": "Example"
+        }
+      },
+      "tags": [1],
+      "date_created": "2025-01-01",
+      "date_updated": "2025-01-02"
+    }
+  },
+  "tags": {
+    "0": { "id": 0, "deleted": false, "path": ["photos"], "color": "Gray" },
+    "1": { "id": 1, "deleted": false, "path": ["photos", "2024"], "color": "Gray" }
+  }
+}"#;
+
+#[test]
+fn legacy_blake3_blob_ids_deserialize() {
+    let legacy: BlobId = serde_json
+        ::from_str(
+            "[56, 167, 131, 56, 88, 53, 61, 134, 18, 113, 195, 148, 252, 1, 31, 90, \
+              76, 237, 80, 193, 177, 96, 34, 165, 2, 108, 153, 205, 81, 15, 141, 35]"
+        )
+        .unwrap();
+    assert_eq!(legacy, BlobId::from(blake3::hash(URL)));
+
+    // saved again, the id is written in the current format and reads back
+    let saved = serde_json::to_string(&legacy).unwrap();
+    assert!(saved.starts_with('"'));
+    assert_eq!(serde_json::from_str::<BlobId>(&saved).unwrap(), legacy);
+
+    // a byte array of the wrong length is not an id
+    assert!(serde_json::from_str::<BlobId>("[1, 2, 3]").is_err());
+}
+
+#[test]
+fn legacy_repository_json_loads() {
+    let mut repo = Repository::load_from_json(LEGACY_REPOSITORY).unwrap();
+    assert!(repo.validate().is_empty());
+    repo.check_index_consistency().unwrap();
+
+    let node = &repo.nodes[&NodeId(0)];
+    assert_eq!(*node.get_date_created(), Utc.with_ymd_and_hms(2025, 1, 1, 0, 0, 0).unwrap());
+    assert_eq!(*node.get_date_updated(), Utc.with_ymd_and_hms(2025, 1, 2, 0, 0, 0).unwrap());
+    assert!(!node.get_uid().is_nil());
+    assert!(repo.tags.values().all(|tag| !tag.get_uid().is_nil()));
+
+    // the data is found under the legacy id
+    let mut store = InMemoryStore::default();
+    store.upload(&BlobId::from(blake3::hash(URL)), URL).unwrap();
+    let blob = node.get_data_ref().get_data_blob();
+    assert_eq!(blob.retrieve_data(&store).unwrap(), URL);
+
+    let photos = repo.get_tag_by_path(vec!["photos".to_string()]).unwrap();
+    assert_eq!(photos, TagId(0));
+    let found = repo.search_bitmap(TagQuery::Tag(photos)).unwrap();
+    assert!(found.contains(0));
+
+    // saving and loading again keeps the records as they were read
+    let reloaded = Repository::load_from_json(&repo.save_to_json().unwrap()).unwrap();
+    assert_eq!(reloaded.nodes, repo.nodes);
+    assert_eq!(reloaded.tags, repo.tags);
+}
@@ -0,0 +1,226 @@
+mod common;
+
+use std::collections::{ BTreeMap, BTreeSet };
+
+use archivum_core::{
+    node::NodeRecord,
+    node_type::{ Bookmark, NodeType },
+    state::{
+        oplog::{ LoggedOperation, Operation },
+        repository::{ Repository, SetTagPathOptions },
+    },
+    tag::TagId,
+    uid::Uid,
+};
+use chrono::{ Duration, Utc };
+use smallvec::smallvec;
+
+use common::{ InMemoryStore, add_bookmark, add_tag };
+
+/// Tag path, color and deletion by uid.
+type Tags = BTreeMap<Uid, (Vec<String>, String, bool)>;
+
+/// Node data, deletion and tag uids by uid.
+type Nodes = BTreeMap<Uid, (String, bool, BTreeSet<Uid>)>;
+
+/// The records of a repository independent of its local ids, which differ
+/// between devices for records created elsewhere.
+fn state(repo: &Repository) -> (Tags, Nodes) {
+    let tags = repo.tags
+        .values()
+        .map(|tag| {
+            let color = format!("{:?}", tag.get_color());
+            (*tag.get_uid(), (tag.get_path().clone(), color, *tag.get_deleted()))
+        })
+        .collect();
+    let nodes = repo.nodes
+        .values()
+        .map(|node| {
+            let data = serde_json::to_string(node.get_data_ref()).unwrap();
+            let tags = node
+                .get_tags()
+                .iter()
+                .map(|tag| *repo.tags[tag].get_uid())
+                .collect();
+            (*node.get_uid(), (data, *node.get_deleted(), tags))
+        })
+        .collect();
+    (tags, nodes)
+}
+
+fn merged(ours: &Repository, theirs: &Repository) -> Repository {
+    let mut repo = ours.clone();
+    repo.merge(theirs);
+    repo
+}
+
+/// A shared starting point and two devices that edited it concurrently.
+fn diverged() -> (Repository, Repository) {
+    let mut store = InMemoryStore::default();
+    let mut base = Repository::new();
+    base.set_actor("base");
+    let inbox = add_tag(&mut base, "inbox");
+    let work = add_tag(&mut base, "work");
+    let node = add_bookmark(&mut base, &mut store, "https://a.example");
+    base.tag_node(node, inbox).unwrap();
+
+    let mut a = base.clone();
+    a.set_actor("a");
+    a.set_tag_path(inbox, vec!["todo"]).unwrap();
+    a.untag_node(node, inbox).unwrap();
+    let created = add_bookmark(&mut a, &mut store, "https://b.example");
+    a.tag_node(created, work).unwrap();
+
+    let mut b = base.clone();
+    b.set_actor("b");
+    b.set_tag_path(inbox, vec!["later"]).unwrap();
+    b.tag_node(node, work).unwrap();
+    b.delete_tag(work).unwrap();
+    add_tag(&mut b, "work/notes");
+
+    (a, b)
+}
+
+#[test]
+fn merge_is_commutative() {
+    let (a, b) = diverged();
+    assert_eq!(state(&merged(&a, &b)), state(&merged(&b, &a)));
+}
+
+#[test]
+fn merge_is_idempotent() {
+    let (a, b) = diverged();
+    let mut ab = merged(&a, &b);
+    let before = state(&ab);
+
+    assert!(ab.merge(&b).nodes.is_empty());
+    assert!(ab.merge(&b).tags.is_empty());
+    let copy = ab.clone();
+    let report = ab.merge(&copy);
+    assert!(report.nodes.is_empty() && report.tags.is_empty());
+    assert_eq!(state(&ab), before);
+    ab.check_index_consistency().unwrap();
+}
+
+#[test]
+fn replicas_converge_in_any_merge_order() {
+    let (a, b) = diverged();
+    let mut c = merged(&a, &Repository::new());
+    c.set_actor("c");
+    let tag = c.tag_by_uid(*a.tags[&TagId(1)].get_uid()).unwrap();
+    c.set_tag_path(tag, vec!["projects"]).unwrap();
+
+    let abc = merged(&merged(&a, &b), &c);
+    let cab = merged(&merged(&c, &a), &b);
+    let bca = merged(&merged(&b, &c), &a);
+    assert_eq!(state(&abc), state(&cab));
+    assert_eq!(state(&abc), state(&bca));
+
+    // after syncing back and forth every replica holds the same records
+    let a = merged(&a, &abc);
+    let b = merged(&b, &cab);
+    let c = merged(&c, &bca);
+    assert_eq!(state(&a), state(&b));
+    assert_eq!(state(&b), state(&c));
+    for repo in [&a, &b, &c] {
+        repo.check_index_consistency().unwrap();
+    }
+}
+
+#[test]
+fn equal_stamps_merge_deterministically() {
+    let mut base = Repository::new();
+    let tag = add_tag(&mut base, "inbox");
+
+    // the same actor writing at the same moment on two devices issues the
+    // same stamp for different values
+    let timestamp = Utc::now();
+    let rename = |path: &str| LoggedOperation {
+        seq: base.oplog().last_seq() + 1,
+        actor: base.oplog().actor().to_string(),
+        timestamp,
+        op: Operation::SetTagPath {
+            tag,
+            path: vec![path.to_string()],
+            options: SetTagPathOptions::default(),
+        },
+    };
+    let mut a = base.clone();
+    a.apply_logged(&rename("todo")).unwrap();
+    let mut b = base.clone();
+    b.apply_logged(&rename("later")).unwrap();
+    assert_eq!(a.tags[&tag].get_clock().path, b.tags[&tag].get_clock().path);
+
+    let ab = merged(&a, &b);
+    let ba = merged(&b, &a);
+    assert_eq!(state(&ab), state(&ba));
+    assert_eq!(ab.tags[&tag].get_path(), ba.tags[&tag].get_path());
+}
+
+#[test]
+fn compacted_tombstones_keep_the_records() {
+    let (a, b) = diverged();
+    let mut ab = merged(&a, &b);
+    let before = state(&ab);
+
+    assert!(ab.compact_tombstones(Utc::now() + Duration::days(1)) > 0);
+    assert_eq!(ab.compact_tombstones(Utc::now() + Duration::days(1)), 0);
+    assert_eq!(state(&ab), before);
+    assert!(ab.nodes.values().all(|node| node.get_clock().tag_removes.is_empty()));
+}
+
+/// The repository as an archive written before uids were kept.
+fn without_uids(repo: &Repository) -> Repository {
+    let mut json: serde_json::Value = serde_json::from_str(&repo.save_to_json().unwrap()).unwrap();
+    for records in ["nodes", "tags"] {
+        for record in json[records].as_object_mut().unwrap().values_mut() {
+            record.as_object_mut().unwrap().remove("uid");
+        }
+    }
+    Repository::load_from_json(&json.to_string()).unwrap()
+}
+
+#[test]
+fn legacy_archives_of_different_devices_stay_apart() {
+    let mut store = InMemoryStore::default();
+    let mut a = Repository::new();
+    add_tag(&mut a, "inbox");
+    let node = add_bookmark(&mut a, &mut store, "https://a.example");
+    std::thread::sleep(std::time::Duration::from_millis(2));
+    let mut b = Repository::new();
+    add_tag(&mut b, "work");
+    add_bookmark(&mut b, &mut store, "https://b.example");
+
+    let ab = merged(&without_uids(&a), &without_uids(&b));
+    assert_eq!(ab.tags.len(), 2);
+    assert_eq!(ab.nodes.len(), 2);
+
+    // copies of one archive still match, also after editing a node
+    let mut copy = a.clone();
+    let blob = copy.upload_data(&mut store, b"https://c.example").unwrap();
+    let data = NodeType::Bookmark(Bookmark::new(blob, None));
+    copy.upsert_node(NodeRecord::new(node, data, smallvec![])).unwrap();
+    let merged = merged(&without_uids(&a), &without_uids(&copy));
+    assert_eq!(merged.tags.len(), 1);
+    assert_eq!(merged.nodes.len(), 1);
+}
+
+#[test]
+fn records_sharing_a_uid_stay_apart() {
+    let mut store = InMemoryStore::default();
+    let mut theirs = Repository::new();
+    let first = add_bookmark(&mut theirs, &mut store, "https://a.example");
+    let second = add_bookmark(&mut theirs, &mut store, "https://b.example");
+    let mut json: serde_json::Value = serde_json
+        ::from_str(&theirs.save_to_json().unwrap())
+        .unwrap();
+    let nodes = &mut json["nodes"];
+    nodes[second.0.to_string()]["uid"] = nodes[first.0.to_string()]["uid"].clone();
+    let theirs = Repository::load_from_json(&json.to_string()).unwrap();
+
+    let mut ours = Repository::new();
+    assert_eq!(ours.merge(&theirs).nodes.len(), 2);
+    assert_eq!(ours.nodes.len(), 2);
+    assert!(ours.merge(&theirs).nodes.is_empty());
+    ours.check_index_consistency().unwrap();
+}
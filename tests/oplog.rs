@@ -0,0 +1,100 @@
+mod common;
+
+use archivum_core::state::{
+    oplog::Checkpoint,
+    repository::{ ChildTagMode, DeleteTagOptions, NodeRefMode, Repository },
+};
+
+use common::{ InMemoryStore, add_bookmark, add_tag };
+
+fn assert_same_records(replayed: &Repository, repo: &Repository) {
+    assert_eq!(replayed.nodes, repo.nodes);
+    assert_eq!(replayed.tags, repo.tags);
+    assert_eq!(replayed.next_node_id, repo.next_node_id);
+    assert_eq!(replayed.next_tag_id, repo.next_tag_id);
+    replayed.check_index_consistency().unwrap();
+}
+
+/// Adds records and tags them, through single operations and a transaction.
+fn create(repo: &mut Repository, store: &mut InMemoryStore) {
+    let docs = add_tag(repo, "docs");
+    let node = add_bookmark(repo, store, "https://a.example");
+    repo.tag_node(node, docs).unwrap();
+    repo.add_bookmark(store, "https://b.example", Some("b"), &["docs/2024", "inbox"]).unwrap();
+    repo.ensure_tag_path(&["archive", "old"], None).unwrap();
+}
+
+/// Moves, deletes and restores some of the records made by `create`.
+fn edit(repo: &mut Repository, store: &mut InMemoryStore) {
+    let docs = repo.get_tag_by_path(vec!["docs".to_string()]).unwrap();
+    let inbox = repo.get_tag_by_path(vec!["inbox".to_string()]).unwrap();
+    repo.set_tag_path(docs, vec!["archive", "docs"]).unwrap();
+    let options = DeleteTagOptions {
+        children: ChildTagMode::Reparent,
+        node_refs: NodeRefMode::Strip,
+    };
+    repo.delete_tag_with(docs, options).unwrap();
+    repo.delete_tag(inbox).unwrap();
+    repo.restore_tag(inbox).unwrap();
+
+    let node = add_bookmark(repo, store, "https://c.example");
+    repo.tag_node(node, inbox).unwrap();
+    repo.untag_node(node, inbox).unwrap();
+    repo.delete_node(node).unwrap();
+}
+
+#[test]
+fn replaying_a_saved_log_reproduces_the_repository() {
+    let mut store = InMemoryStore::default();
+    let mut repo = Repository::new();
+    create(&mut repo, &mut store);
+    edit(&mut repo, &mut store);
+
+    let loaded = Repository::load_from_json(&repo.save_to_json().unwrap()).unwrap();
+    assert_eq!(loaded.oplog().entries().len(), repo.oplog().entries().len());
+
+    let replayed = Repository::replay(loaded.oplog().entries()).unwrap();
+    assert_same_records(&replayed, &repo);
+    assert_eq!(replayed.oplog().last_seq(), repo.oplog().last_seq());
+}
+
+#[test]
+fn checkpoint_and_tail_replay_like_the_whole_log() {
+    let mut store = InMemoryStore::default();
+    let mut repo = Repository::new();
+    create(&mut repo, &mut store);
+
+    let checkpoint = repo.checkpoint();
+    let saved = serde_json::to_string(&checkpoint).unwrap();
+    edit(&mut repo, &mut store);
+
+    let tail = repo.oplog().entries_since(checkpoint.seq);
+    assert!(!tail.is_empty());
+    let checkpoint: Checkpoint = serde_json::from_str(&saved).unwrap();
+    let resumed = Repository::from_checkpoint(checkpoint.clone(), tail).unwrap();
+    assert_same_records(&resumed, &repo);
+
+    // entries the checkpoint already covers are skipped
+    let resumed = Repository::from_checkpoint(checkpoint, repo.oplog().entries()).unwrap();
+    assert_same_records(&resumed, &repo);
+
+    let replayed = Repository::replay(repo.oplog().entries()).unwrap();
+    assert_same_records(&replayed, &repo);
+}
+
+#[test]
+fn repositories_get_actors_of_their_own() {
+    let a = Repository::new();
+    let b = Repository::new();
+    assert_ne!(a.oplog().actor(), b.oplog().actor());
+
+    // a saved name is kept, except the one every repository used to share
+    let mut repo = Repository::new();
+    repo.set_actor("laptop");
+    let loaded = Repository::load_from_json(&repo.save_to_json().unwrap()).unwrap();
+    assert_eq!(loaded.oplog().actor(), "laptop");
+
+    repo.set_actor("local");
+    let loaded = Repository::load_from_json(&repo.save_to_json().unwrap()).unwrap();
+    assert_ne!(loaded.oplog().actor(), "local");
+}
@@ -0,0 +1,158 @@
+mod common;
+
+use archivum_core::{
+    node::NodeRecord,
+    state::{
+        repository::{ RepoError, Repository },
+        three_way::{ Conflict, Resolution },
+    },
+    tag::TagId,
+};
+use smallvec::smallvec;
+
+use common::{ InMemoryStore, add_bookmark, add_tag, bookmark };
+
+fn tag_at(repo: &mut Repository, path: &str) -> TagId {
+    repo.get_tag_by_path(path.split('/').map(str::to_string).collect()).unwrap()
+}
+
+fn paths(repo: &Repository) -> Vec<String> {
+    let mut paths: Vec<String> = repo
+        .iter_tags()
+        .map(|tag| tag.get_path().join("/"))
+        .collect();
+    paths.sort();
+    paths
+}
+
+/// A base and two devices editing it, each with its own actor so the tags
+/// they create get different uids.
+fn sides(base: &Repository) -> (Repository, Repository) {
+    let mut ours = base.clone();
+    ours.set_actor("ours");
+    let mut theirs = base.clone();
+    theirs.set_actor("theirs");
+    (ours, theirs)
+}
+
+#[test]
+fn tags_added_at_one_path_collide() {
+    let mut store = InMemoryStore::default();
+    let mut base = Repository::new();
+    add_tag(&mut base, "a");
+    let (mut ours, mut theirs) = sides(&base);
+    let our_tag = add_tag(&mut ours, "a/b");
+    let their_tag = add_tag(&mut theirs, "a/b");
+    let node = add_bookmark(&mut theirs, &mut store, "https://a.example");
+    theirs.tag_node(node, their_tag).unwrap();
+
+    let mut seen = Vec::new();
+    let report = ours
+        .merge_three_way(&base, &theirs, |conflict| {
+            seen.push(conflict.clone());
+            Resolution::Ours
+        })
+        .unwrap();
+    assert_eq!(seen, vec![Conflict::TagPathCollision {
+        path: vec!["a".to_string(), "b".to_string()],
+        ours: *ours.tags[&our_tag].get_uid(),
+        theirs: *theirs.tags[&their_tag].get_uid(),
+    }]);
+    assert_eq!(report.conflicts.len(), 1);
+    assert_eq!(paths(&ours), ["a", "a/b"]);
+    assert_eq!(tag_at(&mut ours, "a/b"), our_tag);
+    // the node came over without the tag that was left out
+    let node = ours.node_by_uid(*theirs.nodes[&node].get_uid()).unwrap();
+    assert!(ours.nodes[&node].get_tags().is_empty());
+    assert!(ours.validate().is_empty());
+    ours.check_index_consistency().unwrap();
+}
+
+#[test]
+fn colliding_tags_can_take_a_path_of_their_own() {
+    let mut base = Repository::new();
+    add_tag(&mut base, "a");
+    let (mut ours, mut theirs) = sides(&base);
+    add_tag(&mut ours, "a/b");
+    let their_tag = add_tag(&mut theirs, "a/b");
+
+    ours
+        .merge_three_way(&base, &theirs, |_| Resolution::Path(vec!["a".into(), "c".into()]))
+        .unwrap();
+    assert_eq!(paths(&ours), ["a", "a/b", "a/c"]);
+    let moved = tag_at(&mut ours, "a/c");
+    assert_eq!(ours.tags[&moved].get_uid(), theirs.tags[&their_tag].get_uid());
+    assert!(ours.validate().is_empty());
+}
+
+#[test]
+fn tags_renamed_to_one_path_collide() {
+    let mut base = Repository::new();
+    let x = add_tag(&mut base, "x");
+    let y = add_tag(&mut base, "y");
+    let (mut ours, mut theirs) = sides(&base);
+    ours.set_tag_path(x, vec!["p"]).unwrap();
+    theirs.set_tag_path(y, vec!["p"]).unwrap();
+
+    let mut preview = ours.clone();
+    let report = preview.merge_three_way(&base, &theirs, |_| Resolution::Ours).unwrap();
+    assert_eq!(report.conflicts[0].conflict, Conflict::TagPathCollision {
+        path: vec!["p".to_string()],
+        ours: *ours.tags[&x].get_uid(),
+        theirs: *ours.tags[&y].get_uid(),
+    });
+    assert_eq!(paths(&preview), ["p", "y"]);
+    assert_eq!(tag_at(&mut preview, "p"), x);
+
+    // their rename wins and our tag, already at the path, gives way
+    ours.merge_three_way(&base, &theirs, |_| Resolution::Theirs).unwrap();
+    assert_eq!(paths(&ours), ["p"]);
+    assert_eq!(tag_at(&mut ours, "p"), y);
+    assert!(*ours.tags[&x].get_deleted());
+    ours.check_index_consistency().unwrap();
+}
+
+#[test]
+fn tags_taken_along_by_a_move_collide() {
+    let mut base = Repository::new();
+    let a = add_tag(&mut base, "a");
+    let (mut ours, mut theirs) = sides(&base);
+    let ours_child = add_tag(&mut ours, "a/c");
+    theirs.set_tag_path(a, vec!["b"]).unwrap();
+    add_tag(&mut theirs, "b/c");
+
+    let report = ours.merge_three_way(&base, &theirs, |_| Resolution::Ours).unwrap();
+    assert_eq!(report.conflicts.len(), 1);
+    assert_eq!(paths(&ours), ["b", "b/c"]);
+    assert_eq!(tag_at(&mut ours, "b/c"), ours_child);
+    assert!(ours.validate().is_empty());
+    ours.check_index_consistency().unwrap();
+}
+
+#[test]
+fn conflicts_can_be_resolved_with_values_of_their_own() {
+    let mut store = InMemoryStore::default();
+    let mut base = Repository::new();
+    let node = add_bookmark(&mut base, &mut store, "https://a.example");
+    let (mut ours, mut theirs) = sides(&base);
+    for (repo, url) in [(&mut ours, "https://b.example"), (&mut theirs, "https://c.example")] {
+        let data = bookmark(repo, &mut store, url);
+        repo.upsert_node(NodeRecord::new(node, data, smallvec![])).unwrap();
+    }
+    let custom = bookmark(&mut ours, &mut store, "https://d.example");
+
+    // a value that does not fit the conflict fails the merge and changes nothing
+    let before = ours.clone();
+    let result = ours.merge_three_way(&base, &theirs, |_| Resolution::Path(vec!["x".into()]));
+    assert!(matches!(result, Err(RepoError::PatchConflict(_))));
+    assert_eq!(ours.nodes, before.nodes);
+
+    let report = ours
+        .merge_three_way(&base, &theirs, |conflict| {
+            assert!(matches!(conflict, Conflict::NodeData { .. }));
+            Resolution::Data(Box::new(custom.clone()))
+        })
+        .unwrap();
+    assert_eq!(report.conflicts.len(), 1);
+    assert_eq!(ours.nodes[&node].get_data_ref(), &custom);
+}